@@ -0,0 +1,459 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+
+use crate::output::Format;
+
+/// Invocation holds a parsed command line: the command to run along with the
+/// global options that apply to it. Global options are parsed first, so the
+/// requested format is known even when the command itself is invalid.
+pub struct Invocation {
+    pub store: Option<String>,
+    pub all_stores: bool,
+    pub format: Format,
+    pub command: Result<Command, String>,
+}
+
+pub enum Command {
+    Help,
+    Version,
+    List { group: Option<String> },
+    Get { group: Option<String>, name: String },
+    Set { group: String, name: String, value: ValueSource, trim: bool },
+    Remove { group: String, name: String },
+    RemoveGroup { group: String },
+    Edit { group: String, name: String },
+    Show { group: Option<String>, name: String, id_only: bool },
+    Pick { query: String },
+    Search { query: String, threshold: Option<f64> },
+    Grep { pattern: String, ignore_case: bool, names: bool },
+    History { group: String, name: String },
+    Restore { group: String, name: String, rev: Option<String> },
+    Sync { strategy: Option<Strategy>, dry_run: bool },
+    SyncStatus,
+    SyncInit { url: String },
+    SyncMerge { url: String },
+    Reindex,
+    Migrate,
+    Stores(StoresCommand),
+}
+
+/// ValueSource indicates where the value of an item being set comes from.
+pub enum ValueSource {
+    Argument(String),
+    Stdin,
+    File(PathBuf),
+}
+
+/// Strategy decides what becomes of items changed differently on both sides
+/// of a sync. When none is provided, people are asked about each item on a
+/// terminal, and both versions are kept otherwise.
+#[derive(Clone, Copy)]
+pub enum Strategy {
+    KeepLocal,
+    KeepRemote,
+    KeepBoth,
+    Edit,
+}
+
+pub enum StoresCommand {
+    List,
+    Add { name: String, path: Option<PathBuf> },
+    Remove { name: String },
+    Default { name: String },
+}
+
+// Words that name a subcommand when provided as the first argument. Anything
+// else is handled as one of the shorthand forms.
+const COMMANDS: [&str; 19] = [
+    "help", "ls", "all", "get", "set", "rm", "rm-item", "rm-group", "edit", "show", "pick",
+    "search", "grep", "history", "restore", "sync", "reindex", "migrate", "stores",
+];
+
+struct Parser {
+    args: VecDeque<String>,
+    flags: Vec<String>,
+    // Whether all positional arguments were provided after `--`, in which
+    // case the first one is never taken as a command.
+    literal: bool,
+    file: Option<PathBuf>,
+    threshold: Option<f64>,
+    stdin_piped: bool,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<String> {
+        self.args.pop_front()
+    }
+
+    fn remaining(&self) -> usize {
+        self.args.len()
+    }
+
+    // Removes a command-specific flag, returning whether it was provided.
+    fn flag(&mut self, flag: &str) -> bool {
+        match self.flags.iter().position(|f| f == flag) {
+            None => false,
+            Some(pos) => {
+                self.flags.remove(pos);
+                true
+            }
+        }
+    }
+
+    // Takes the next argument as a group or item name.
+    fn name(&mut self) -> String {
+        self.next().unwrap().to_lowercase()
+    }
+
+    // Takes all remaining arguments as a single value, joined by spaces. A
+    // single dash reads the value from stdin, and no arguments at all read
+    // it from --file, or from stdin when it is piped.
+    fn value(&mut self) -> Result<ValueSource, String> {
+        // Values starting with a dash are taken as options, and would
+        // otherwise be reported as a missing value.
+        if let Some(flag) = self.flags.first() {
+            return Err(unknown_option(flag));
+        }
+        let args = self.args.drain(..).collect::<Vec<_>>();
+        match (args.as_slice(), self.file.take()) {
+            ([], Some(path)) => Ok(ValueSource::File(path)),
+            (_, Some(_)) => Err("A value cannot be provided along with --file".to_string()),
+            ([dash], None) if dash == "-" => Ok(ValueSource::Stdin),
+            ([], None) if self.stdin_piped => Ok(ValueSource::Stdin),
+            ([], None) => Err("Missing value. Provide it as an argument, through --file PATH, or pipe it to stdin".to_string()),
+            (args, None) => Ok(ValueSource::Argument(args.join(" "))),
+        }
+    }
+
+    fn expect(&self, usage: &str, min: usize, max: usize) -> Result<(), String> {
+        if self.remaining() < min || self.remaining() > max {
+            Err(format!("Usage: oxio {}", usage))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn finish(self, command: Command) -> Result<Command, String> {
+        if self.file.is_some() {
+            return Err(unknown_option("--file"));
+        }
+        if self.threshold.is_some() {
+            return Err(unknown_option("--threshold"));
+        }
+        match self.flags.first() {
+            None => Ok(command),
+            Some(flag) => Err(unknown_option(flag)),
+        }
+    }
+}
+
+fn unknown_option(flag: &str) -> String {
+    format!("Unknown option {}. Use -- before values starting with a dash.", flag)
+}
+
+/// Parses the provided arguments, excluding the program name. Global options
+/// may appear anywhere before a `--` argument, after which all arguments are
+/// taken literally. A command name following `--` is therefore taken as an
+/// item or group name, as in `oxio -- sync`. When stdin is a pipe or a
+/// redirected file, values that are not provided otherwise are read from it.
+pub fn parse(args: Vec<String>, stdin_piped: bool) -> Result<Invocation, String> {
+    let mut store = None;
+    let mut all_stores = false;
+    let mut help = false;
+    let mut version = false;
+    let mut flags = vec![];
+    let mut positional = VecDeque::new();
+    let mut literal = false;
+    let mut positional_before = 0;
+    let mut file = None;
+    let mut threshold = None;
+    let mut format = Format::Text;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--" => {
+                literal = true;
+                positional_before = positional.len();
+                positional.extend(args.by_ref());
+                break;
+            }
+            "--store" => match args.next() {
+                None => return Err("Missing store name or path for --store".to_string()),
+                Some(s) => store = Some(s),
+            },
+            "--file" => match args.next() {
+                None => return Err("Missing path for --file".to_string()),
+                Some(f) => file = Some(expand_path(&f)),
+            },
+            "--threshold" => match args.next() {
+                None => return Err("Missing value for --threshold".to_string()),
+                Some(t) => threshold = Some(parse_threshold(&t)?),
+            },
+            "--format" => match args.next() {
+                None => return Err("Missing format for --format".to_string()),
+                Some(f) => format = parse_format(&f)?,
+            },
+            "--all-stores" => all_stores = true,
+            "-h" | "--help" => help = true,
+            "-V" | "--version" => version = true,
+            a if a.starts_with("--store=") => store = Some(a["--store=".len()..].to_string()),
+            a if a.starts_with("--file=") => file = Some(expand_path(&a["--file=".len()..])),
+            a if a.starts_with("--threshold=") => threshold = Some(parse_threshold(&a["--threshold=".len()..])?),
+            a if a.starts_with("--format=") => format = parse_format(&a["--format=".len()..])?,
+            a if a.starts_with('-') && a.len() > 1 => flags.push(arg),
+            _ => positional.push_back(arg),
+        }
+    }
+
+    let mut parser = Parser {
+        literal: literal && positional_before == 0,
+        args: positional,
+        flags,
+        file,
+        threshold,
+        stdin_piped,
+    };
+    let command = if help {
+        Ok(Command::Help)
+    } else if version {
+        Ok(Command::Version)
+    } else {
+        parse_command(&mut parser)
+    };
+    let command = command.and_then(|c| parser.finish(c));
+    Ok(Invocation { store, all_stores, format, command })
+}
+
+fn parse_format(name: &str) -> Result<Format, String> {
+    Format::parse(name).ok_or_else(|| format!("Unknown format {}. Use text, json, yaml or tsv.", name))
+}
+
+fn parse_threshold(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(t) if (0.0..=1.0).contains(&t) => Ok(t),
+        _ => Err(format!("Invalid threshold {}. Use a number between 0 and 1.", value)),
+    }
+}
+
+fn parse_command(p: &mut Parser) -> Result<Command, String> {
+    let first = match p.args.front() {
+        None => return Ok(Command::Help),
+        Some(a) => a.to_lowercase(),
+    };
+    if p.literal || !COMMANDS.contains(&first.as_str()) {
+        return parse_shorthand(p);
+    }
+    p.next();
+
+    Ok(match first.as_str() {
+        "help" => {
+            p.expect("help", 0, 0)?;
+            Command::Help
+        }
+        "ls" | "all" => {
+            p.expect("ls [GROUPNAME]", 0, 1)?;
+            Command::List { group: p.next().map(|g| g.to_lowercase()) }
+        }
+        "get" => {
+            p.expect("get [GROUPNAME] ITEMNAME", 1, 2)?;
+            let group = if p.remaining() == 2 { Some(p.name()) } else { None };
+            Command::Get { group, name: p.name() }
+        }
+        "set" => {
+            let trim = p.flag("--trim");
+            p.expect("set [--trim] GROUPNAME ITEMNAME [VALUE|-|--file PATH]", 2, usize::MAX)?;
+            Command::Set { group: p.name(), name: p.name(), value: p.value()?, trim }
+        }
+        "rm" if p.flag("--group") => {
+            p.expect("rm --group GROUPNAME", 1, 1)?;
+            Command::RemoveGroup { group: p.name() }
+        }
+        "rm" | "rm-item" => {
+            p.expect("rm GROUPNAME ITEMNAME", 2, 2)?;
+            Command::Remove { group: p.name(), name: p.name() }
+        }
+        "rm-group" => {
+            p.expect("rm-group GROUPNAME", 1, 1)?;
+            Command::RemoveGroup { group: p.name() }
+        }
+        "edit" => {
+            p.expect("edit GROUPNAME ITEMNAME", 2, 2)?;
+            Command::Edit { group: p.name(), name: p.name() }
+        }
+        "show" => {
+            let id_only = p.flag("--id");
+            p.expect("show [--id] [GROUPNAME] ITEMNAME", 1, 2)?;
+            let group = if p.remaining() == 2 { Some(p.name()) } else { None };
+            Command::Show { group, name: p.name(), id_only }
+        }
+        "pick" => Command::Pick { query: p.args.drain(..).collect::<Vec<_>>().join(" ") },
+        "search" => {
+            p.expect("search [--threshold N] QUERY", 1, usize::MAX)?;
+            let query = p.args.drain(..).collect::<Vec<_>>().join(" ");
+            Command::Search { query, threshold: p.threshold.take() }
+        }
+        "grep" => {
+            let ignore_case = p.flag("-i");
+            let names = p.flag("--names");
+            p.expect("grep [-i] [--names] PATTERN", 1, 1)?;
+            Command::Grep { pattern: p.next().unwrap(), ignore_case, names }
+        }
+        "history" => {
+            p.expect("history GROUPNAME ITEMNAME", 2, 2)?;
+            Command::History { group: p.name(), name: p.name() }
+        }
+        "restore" => {
+            p.expect("restore GROUPNAME ITEMNAME [REV]", 2, 3)?;
+            Command::Restore { group: p.name(), name: p.name(), rev: p.next() }
+        }
+        "sync" => parse_sync(p)?,
+        "reindex" => {
+            p.expect("reindex", 0, 0)?;
+            Command::Reindex
+        }
+        "migrate" => {
+            p.expect("migrate", 0, 0)?;
+            Command::Migrate
+        }
+        "stores" => Command::Stores(parse_stores(p)?),
+        _ => unreachable!(),
+    })
+}
+
+fn parse_sync(p: &mut Parser) -> Result<Command, String> {
+    let usage = "sync [--dry-run] [--keep-local|--keep-remote|--keep-both|--edit] | sync status | sync [init|merge URL]";
+    let sub = match p.next() {
+        None => return Ok(Command::Sync { strategy: parse_strategy(p)?, dry_run: p.flag("--dry-run") }),
+        Some(s) => s.to_lowercase(),
+    };
+    if sub == "status" {
+        p.expect("sync status", 0, 0)?;
+        return Ok(Command::SyncStatus);
+    }
+    p.expect(usage, 1, 1)?;
+    let url = p.next().unwrap();
+    match sub.as_str() {
+        "init" => Ok(Command::SyncInit { url }),
+        "merge" => Ok(Command::SyncMerge { url }),
+        _ => Err(format!("Unknown command 'sync {}'. Usage: oxio {}", sub, usage)),
+    }
+}
+
+fn parse_strategy(p: &mut Parser) -> Result<Option<Strategy>, String> {
+    let strategies = [
+        ("--keep-local", Strategy::KeepLocal),
+        ("--keep-remote", Strategy::KeepRemote),
+        ("--keep-both", Strategy::KeepBoth),
+        ("--edit", Strategy::Edit),
+    ];
+    let mut chosen = None;
+    for (flag, strategy) in strategies.iter() {
+        if p.flag(flag) {
+            if chosen.is_some() {
+                return Err("Only one of --keep-local, --keep-remote, --keep-both and --edit may be provided".to_string());
+            }
+            chosen = Some(*strategy);
+        }
+    }
+    Ok(chosen)
+}
+
+fn parse_stores(p: &mut Parser) -> Result<StoresCommand, String> {
+    let sub = p.next().map(|s| s.to_lowercase()).unwrap_or_else(|| "list".to_string());
+    Ok(match sub.as_str() {
+        "list" => {
+            p.expect("stores list", 0, 0)?;
+            StoresCommand::List
+        }
+        "add" => {
+            p.expect("stores add STORE [PATH]", 1, 2)?;
+            let name = p.next().unwrap();
+            let path = p.next().map(|p| expand_path(&p));
+            StoresCommand::Add { name, path }
+        }
+        "remove" => {
+            p.expect("stores remove STORE", 1, 1)?;
+            StoresCommand::Remove { name: p.next().unwrap() }
+        }
+        "default" => {
+            p.expect("stores default STORE", 1, 1)?;
+            StoresCommand::Default { name: p.next().unwrap() }
+        }
+        _ => return Err(format!("Unknown command 'stores {}'", sub)),
+    })
+}
+
+// Shorthand forms kept from earlier versions: `oxio ITEMNAME`,
+// `oxio GROUPNAME ITEMNAME` and `oxio GROUPNAME ITEMNAME VALUE`. The second
+// form sets the item instead when a value is piped or provided via --file.
+fn parse_shorthand(p: &mut Parser) -> Result<Command, String> {
+    let trim = p.flag("--trim");
+    let reads_value = p.file.is_some() || p.stdin_piped;
+    Ok(match p.remaining() {
+        1 => Command::Get { group: None, name: p.name() },
+        2 if !reads_value => Command::Get { group: Some(p.name()), name: p.name() },
+        _ => Command::Set { group: p.name(), name: p.name(), value: p.value()?, trim },
+    })
+}
+
+fn expand_path(path: &str) -> PathBuf {
+    PathBuf::from(shellexpand::tilde(path).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Result<Command, String> {
+        parse_with(args, false)
+    }
+
+    fn parse_with(args: &[&str], stdin_piped: bool) -> Result<Command, String> {
+        parse(args.iter().map(|a| a.to_string()).collect(), stdin_piped).unwrap().command
+    }
+
+    #[test]
+    fn values_starting_with_a_dash_require_a_separator() {
+        for args in [&["set", "g", "n", "-5"][..], &["g", "n", "-----BEGIN KEY-----"]].iter() {
+            match command(args) {
+                Err(e) => assert!(e.starts_with("Unknown option -"), "{}", e),
+                Ok(_) => panic!("{:?} was accepted", args),
+            }
+        }
+        match command(&["set", "g", "n", "--", "-5"]) {
+            Ok(Command::Set { value: ValueSource::Argument(v), .. }) => assert_eq!(v, "-5"),
+            _ => panic!("value after -- was not taken literally"),
+        }
+    }
+
+    #[test]
+    fn options_are_accepted_anywhere() {
+        match command(&["set", "--trim", "g", "n", "--file", "v.txt"]) {
+            Ok(Command::Set { group, name, value: ValueSource::File(_), trim: true }) => {
+                assert_eq!((group.as_str(), name.as_str()), ("g", "n"))
+            }
+            _ => panic!("set options were not parsed"),
+        }
+        assert!(matches!(command(&["g", "n"]), Ok(Command::Get { group: Some(_), .. })));
+    }
+
+    #[test]
+    fn group_and_name_read_piped_values() {
+        match parse_with(&["gif", "magic"], true) {
+            Ok(Command::Set { group, name, value: ValueSource::Stdin, .. }) => {
+                assert_eq!((group.as_str(), name.as_str()), ("gif", "magic"))
+            }
+            _ => panic!("piped value was not set"),
+        }
+        assert!(matches!(parse_with(&["set", "gif", "magic"], true), Ok(Command::Set { value: ValueSource::Stdin, .. })));
+        // Single names are always looked up
+        assert!(matches!(parse_with(&["magic"], true), Ok(Command::Get { group: None, .. })));
+    }
+
+    #[test]
+    fn group_and_name_are_looked_up_from_terminals() {
+        assert!(matches!(parse_with(&["gif", "magic"], false), Ok(Command::Get { group: Some(_), .. })));
+        assert!(parse_with(&["set", "gif", "magic"], false).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use clipboard::{ClipboardContext, ClipboardProvider};
+use oxio::{Error, Value};
+
+/// Copies a value to the system clipboard. Text values are handled by the
+/// clipboard crate, while binary values are handed to a platform tool able to
+/// advertise their content type, so they can be pasted as images, documents,
+/// and so on.
+pub fn copy(value: &Value, content_type: &str) -> oxio::Result<()> {
+    copy_value(value, content_type).map_err(Error::Clipboard)
+}
+
+fn copy_value(value: &Value, content_type: &str) -> Result<(), String> {
+    match value {
+        Value::Text(s) => {
+            let mut clip: ClipboardContext = ClipboardProvider::new().map_err(|e| e.to_string())?;
+            clip.set_contents(s.clone()).map_err(|e| e.to_string())
+        }
+        Value::Binary(b) => copy_binary(b, content_type),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn copy_binary(bytes: &[u8], content_type: &str) -> Result<(), String> {
+    use std::process::Command;
+
+    let class = match content_type {
+        "image/png" => "PNGf",
+        "image/jpeg" => "JPEG",
+        "image/gif" => "GIFf",
+        "image/tiff" => "TIFF",
+        "application/pdf" => "PDF ",
+        _ => return Err(format!("copying {} values is not supported on this platform", content_type)),
+    };
+    let path = std::env::temp_dir().join(format!("oxio-clip-{}", std::process::id()));
+    std::fs::write(&path, bytes).map_err(|e| e.to_string())?;
+    let script = format!("set the clipboard to (read (POSIX file \"{}\") as «class {}»)", path.to_str().unwrap(), class);
+    let status = Command::new("osascript").arg("-e").arg(script).status();
+    let _ = std::fs::remove_file(&path);
+    match status {
+        Ok(s) if s.success() => Ok(()),
+        Ok(s) => Err(format!("osascript exited with {}", s)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn copy_binary(bytes: &[u8], content_type: &str) -> Result<(), String> {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut tools = vec![];
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        tools.push(("wl-copy", vec!["--type", content_type]));
+    }
+    tools.push(("xclip", vec!["-selection", "clipboard", "-t", content_type, "-i"]));
+
+    for (tool, args) in tools {
+        let mut child = match Command::new(tool).args(&args).stdin(Stdio::piped()).spawn() {
+            Err(_) => continue,
+            Ok(c) => c,
+        };
+        child.stdin.take().unwrap().write_all(bytes).map_err(|e| e.to_string())?;
+        return match child.wait() {
+            Ok(s) if s.success() => Ok(()),
+            Ok(s) => Err(format!("{} exited with {}", tool, s)),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+    Err("copying binary values requires wl-copy or xclip to be installed".to_string())
+}
+
+#[cfg(not(unix))]
+fn copy_binary(_bytes: &[u8], _content_type: &str) -> Result<(), String> {
+    Err("copying binary values is not supported on this platform".to_string())
+}
@@ -1,32 +1,253 @@
 use crate::fs;
 use std::collections::HashSet;
-use crate::result::Operation;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use crate::result::{Error, IoContext, Operation};
+use sha1::{Digest, Sha1};
+
+/// Value holds the contents of an item. Values that are not valid UTF-8,
+/// such as images or keyfiles, are kept as raw bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Value {
+    /// Creates a value from raw bytes, keeping it as text whenever it is
+    /// valid UTF-8.
+    pub fn from_bytes(bytes: Vec<u8>) -> Value {
+        match String::from_utf8(bytes) {
+            Ok(s) => Value::Text(s),
+            Err(e) => Value::Binary(e.into_bytes()),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Value::Text(s) => s.as_bytes(),
+            Value::Binary(b) => b.as_slice(),
+        }
+    }
+
+    /// Returns the value as text, unless it is binary.
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(s) => Some(s.as_str()),
+            Value::Binary(_) => None,
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Value::Binary(_))
+    }
+
+    pub fn len(&self) -> usize {
+        self.as_bytes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Guesses the content type of this value. Text values are always
+    /// text/plain, while binary values are identified by their signature.
+    pub fn guess_content_type(&self) -> &'static str {
+        let bytes = match self {
+            Value::Text(_) => return "text/plain",
+            Value::Binary(b) => b.as_slice(),
+        };
+        let signatures: [(&[u8], &'static str); 7] = [
+            (b"\x89PNG\r\n\x1a\n", "image/png"),
+            (b"\xff\xd8\xff", "image/jpeg"),
+            (b"GIF87a", "image/gif"),
+            (b"GIF89a", "image/gif"),
+            (b"%PDF-", "application/pdf"),
+            (b"PK\x03\x04", "application/zip"),
+            (b"\x1f\x8b", "application/gzip"),
+        ];
+        signatures.iter()
+            .find(|(sig, _)| bytes.starts_with(sig))
+            .map(|(_, t)| *t)
+            .unwrap_or("application/octet-stream")
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Text(String::new())
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Binary(b) => write!(f, "<binary, {} bytes>", b.len()),
+        }
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Text(s.to_string())
+    }
+}
+
+impl From<&String> for Value {
+    fn from(s: &String) -> Self {
+        Value::Text(s.clone())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Text(s)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::from_bytes(b)
+    }
+}
+
+/// Metadata carries additional information about an item. Timestamps are
+/// expressed in seconds since the Unix epoch, and are absent for items
+/// written before they were tracked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub created: Option<u64>,
+    pub updated: Option<u64>,
+    pub tags: Vec<String>,
+    pub description: Option<String>,
+    pub content_type: String,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata {
+            created: None,
+            updated: None,
+            tags: vec![],
+            description: None,
+            content_type: "text/plain".to_string(),
+        }
+    }
+}
+
+/// Summary describes an item's value without holding it entirely, allowing
+/// items to be listed straight from the index.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Beginning of the value, up to PREVIEW_LENGTH characters. Always empty
+    /// for binary values.
+    pub preview: String,
+    /// Length of the value, in bytes.
+    pub length: u64,
+    /// SHA-1 digest of the value, as a hex string.
+    pub hash: String,
+}
+
+/// Maximum amount of characters kept by [`Summary::preview`].
+pub const PREVIEW_LENGTH: usize = 256;
+
+impl Summary {
+    pub fn of(value: &Value) -> Summary {
+        let preview = match value {
+            Value::Text(s) => s.chars().take(PREVIEW_LENGTH).collect(),
+            Value::Binary(_) => String::new(),
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(value.as_bytes());
+        Summary {
+            preview,
+            length: value.len() as u64,
+            hash: format!("{:x}", hasher.finalize()),
+        }
+    }
+
+    /// Returns whether the preview holds only part of the value.
+    pub fn is_truncated(&self) -> bool {
+        (self.preview.len() as u64) < self.length
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Item {
     pub group: String,
     pub name: String,
-    pub value: String,
+    /// Value of the item. Items obtained through listings carry an empty
+    /// value until loaded; use their summary instead.
+    pub value: Value,
     pub filename: String,
+    pub meta: Metadata,
+    /// Summary of the value, as of the last time the item was written or
+    /// loaded.
+    pub summary: Summary,
 }
 
 impl Item {
-    pub fn fill_value(self: &mut Item) -> Operation {
-        let path = fs::cache_path().join(&self.filename);
+    /// Creates a new item with default metadata. The item is not written to
+    /// any store until passed to [`crate::Store::save`].
+    pub fn new<V: Into<Value>>(group: &str, name: &str, value: V) -> Item {
+        let value = value.into();
+        let meta = Metadata { content_type: value.guess_content_type().to_string(), ..Metadata::default() };
+        Item {
+            group: group.to_string(),
+            name: name.to_string(),
+            summary: Summary::of(&value),
+            value,
+            filename: String::new(),
+            meta,
+        }
+    }
+
+    /// Returns the stable identifier of the item, derived from its group and
+    /// name. Unlike its value, the identifier does not change across edits.
+    pub fn id(&self) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(self.group.as_bytes());
+        hasher.update([0x00u8]);
+        hasher.update(self.name.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub(crate) fn fill_value(self: &mut Item, cache: &Path) -> Operation {
+        let path = cache.join(&self.filename);
         let item = fs::read_item(&path)?;
         self.value = item.value;
+        self.meta = item.meta;
+        self.summary = item.summary;
         Ok(())
     }
 
-    pub fn delete(self: &mut Item) -> Operation {
-        let path = fs::cache_path().join(&self.filename);
+    pub(crate) fn delete(&self, cache: &Path) -> Operation {
+        let path = cache.join(&self.filename);
         if path.exists() {
-            std::fs::remove_file(path)?;
+            std::fs::remove_file(&path).at(&path)?;
         }
         Ok(())
     }
 }
 
+/// CorruptItem represents a file in a store that could not be read as an
+/// item.
+#[derive(Debug)]
+pub struct CorruptItem {
+    pub filename: String,
+    pub error: Error,
+}
+
+/// Reindex describes the outcome of indexing a store.
+#[derive(Debug)]
+pub struct Reindex {
+    /// Amount of items indexed.
+    pub items: usize,
+    /// Files that were left out of the index since they could not be read.
+    pub corrupt: Vec<CorruptItem>,
+}
+
 pub fn group_items(items: Vec<Item>) -> Vec<(String, Vec<Item>)> {
     let mut groups = HashSet::new();
     for item in &items {
@@ -34,18 +255,16 @@ pub fn group_items(items: Vec<Item>) -> Vec<(String, Vec<Item>)> {
     }
 
     let mut result = Vec::with_capacity(groups.len());
-    let mut groups = groups.iter().to_owned().collect::<Vec<_>>();
+    let mut groups = groups.iter().collect::<Vec<_>>();
     groups.sort();
 
     for &group in groups {
         let mut items = items
             .iter()
-            .to_owned()
-            .filter(|&i| i.group.eq(&group.to_owned()))
+            .filter(|&i| i.group.eq(group))
             .collect::<Vec<_>>();
         items.sort_by_key(|i| &i.name);
         result.push((group.to_owned(), items
-            .to_owned()
             .into_iter()
             .cloned()
             .collect::<Vec<_>>()));
@@ -0,0 +1,434 @@
+use std::convert::TryInto;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::entities::{Item, Metadata, Summary, Value};
+use crate::result::{Error, Result};
+
+/// Items are stored with a header composed of MAGIC followed by a single
+/// version byte. MAGIC starts with 0xFF, which never appears in UTF-8, so it
+/// cannot be mistaken for the group name that starts legacy items.
+pub(crate) const MAGIC: &[u8] = b"\xFFOXIO";
+pub(crate) const VERSION: u8 = 1;
+
+// After the header, an item is a sequence of fields, each composed of a tag
+// byte, a little-endian u32 length, and the field's contents. Readers skip
+// tags they do not know about, so fields can be added without bumping
+// VERSION.
+const TAG_GROUP: u8 = 1;
+const TAG_NAME: u8 = 2;
+const TAG_VALUE: u8 = 3;
+const TAG_CONTENT_TYPE: u8 = 4;
+const TAG_DESCRIPTION: u8 = 5;
+const TAG_TAG: u8 = 6;
+const TAG_CREATED: u8 = 7;
+const TAG_UPDATED: u8 = 8;
+
+/// Format indicates which on-disk format an item was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Items written by oxio 0.1, composed of group, name and value
+    /// separated by NUL bytes.
+    Legacy,
+    /// Items written with a header, carrying the format version.
+    Versioned(u8),
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn push_field(buf: &mut Vec<u8>, tag: u8, data: &[u8]) {
+    buf.push(tag);
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+}
+
+pub(crate) fn encode(item: &Item) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(MAGIC.len() + 1 + item.group.len() + item.name.len() + item.value.len() + 64);
+    buf.extend_from_slice(MAGIC);
+    buf.push(VERSION);
+    push_field(&mut buf, TAG_GROUP, item.group.as_bytes());
+    push_field(&mut buf, TAG_NAME, item.name.as_bytes());
+    push_field(&mut buf, TAG_VALUE, item.value.as_bytes());
+    push_field(&mut buf, TAG_CONTENT_TYPE, item.meta.content_type.as_bytes());
+    if let Some(description) = &item.meta.description {
+        push_field(&mut buf, TAG_DESCRIPTION, description.as_bytes());
+    }
+    for tag in &item.meta.tags {
+        push_field(&mut buf, TAG_TAG, tag.as_bytes());
+    }
+    if let Some(created) = item.meta.created {
+        push_field(&mut buf, TAG_CREATED, &created.to_le_bytes());
+    }
+    if let Some(updated) = item.meta.updated {
+        push_field(&mut buf, TAG_UPDATED, &updated.to_le_bytes());
+    }
+    buf
+}
+
+fn corrupt(path: &Path, reason: &str) -> Error {
+    Error::Corrupt { path: path.to_path_buf(), reason: reason.to_string() }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string()
+}
+
+fn text(data: &[u8], path: &Path) -> Result<String> {
+    String::from_utf8(data.to_vec()).map_err(|_| corrupt(path, "invalid UTF-8 value"))
+}
+
+fn number(data: &[u8], path: &Path) -> Result<u64> {
+    data.try_into()
+        .map(u64::from_le_bytes)
+        .map_err(|_| corrupt(path, "invalid number"))
+}
+
+// Splits data into chunks prefixed by a header of `tag_len` bytes and a
+// little-endian u32 length, yielding each header along with its chunk.
+// Iteration stops at the first chunk not fitting in the data, which is
+// reported as corrupt.
+fn chunks<'a>(data: &'a [u8], path: &'a Path, tag_len: usize, what: &'static str)
+              -> impl Iterator<Item = Result<(&'a [u8], &'a [u8])>> + 'a {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        if pos >= data.len() {
+            return None;
+        }
+        let start = pos;
+        // Nothing is read past a corrupt chunk
+        pos = data.len();
+        let header = match data[start..].get(..tag_len + 4) {
+            None => return Some(Err(corrupt(path, &format!("truncated {} header", what)))),
+            Some(h) => h,
+        };
+        let len = u32::from_le_bytes(header[tag_len..].try_into().unwrap()) as usize;
+        let body = start + header.len();
+        match data[body..].get(..len) {
+            None => Some(Err(corrupt(path, &format!("truncated {}", what)))),
+            Some(chunk) => {
+                pos = body + len;
+                Some(Ok((&header[..tag_len], chunk)))
+            }
+        }
+    })
+}
+
+// Splits the contents of an item or index entry into its tagged fields.
+fn fields<'a>(data: &'a [u8], path: &'a Path) -> impl Iterator<Item = Result<(u8, &'a [u8])>> + 'a {
+    chunks(data, path, 1, "field").map(|f| f.map(|(tag, field)| (tag[0], field)))
+}
+
+// Splits the entries of the index or trigram index, following their header.
+fn entries<'a>(data: &'a [u8], path: &'a Path) -> impl Iterator<Item = Result<&'a [u8]>> + 'a {
+    chunks(data, path, 0, "entry").map(|e| e.map(|(_, entry)| entry))
+}
+
+/// Decodes an item from its file contents, accepting both the legacy and
+/// versioned formats.
+pub(crate) fn decode(bytes: &[u8], path: &Path) -> Result<(Item, Format)> {
+    if !bytes.starts_with(MAGIC) {
+        return decode_legacy(bytes, path).map(|i| (i, Format::Legacy));
+    }
+
+    let version = match bytes.get(MAGIC.len()) {
+        None => return Err(corrupt(path, "missing format version")),
+        Some(v) => *v,
+    };
+    if version > VERSION {
+        return Err(Error::new(&format!("Item {} was written by a newer version of oxio (format {}). Please upgrade oxio.", path.display(), version)));
+    }
+
+    let mut item = Item::new("", "", "");
+    item.filename = file_name(path);
+    item.meta.content_type = String::new();
+    let mut value = None;
+    let (mut has_group, mut has_name) = (false, false);
+    for field in fields(&bytes[MAGIC.len() + 1..], path) {
+        let (tag, data) = field?;
+        match tag {
+            TAG_GROUP => {
+                item.group = text(data, path)?;
+                has_group = true;
+            }
+            TAG_NAME => {
+                item.name = text(data, path)?;
+                has_name = true;
+            }
+            TAG_VALUE => value = Some(data.to_vec()),
+            TAG_CONTENT_TYPE => item.meta.content_type = text(data, path)?,
+            TAG_DESCRIPTION => item.meta.description = Some(text(data, path)?),
+            TAG_TAG => item.meta.tags.push(text(data, path)?),
+            TAG_CREATED => item.meta.created = Some(number(data, path)?),
+            TAG_UPDATED => item.meta.updated = Some(number(data, path)?),
+            _ => {}
+        }
+    }
+
+    let value = match value {
+        Some(v) if has_group && has_name => v,
+        _ => return Err(corrupt(path, "missing group, name or value")),
+    };
+    if item.meta.content_type.is_empty() {
+        item.meta.content_type = Metadata::default().content_type;
+    }
+    // Text values are only kept as such when they were stored as text; a
+    // binary value that happens to be valid UTF-8 remains binary.
+    item.value = if item.meta.content_type.starts_with("text/") {
+        Value::from_bytes(value)
+    } else {
+        Value::Binary(value)
+    };
+    item.summary = Summary::of(&item.value);
+    Ok((item, Format::Versioned(version)))
+}
+
+fn decode_legacy(bytes: &[u8], path: &Path) -> Result<Item> {
+    let mut parts = bytes.splitn(3, |b| *b == 0x00u8);
+    let (group, name, value) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(g), Some(n), Some(v)) => (g, n, v),
+        _ => return Err(corrupt(path, "expected group, name and value")),
+    };
+    // Legacy writers did not terminate values, but older readers tolerated a
+    // trailing separator.
+    let value = value.strip_suffix(&[0x00u8]).unwrap_or(value);
+    let mut item = Item::new(&text(group, path)?, &text(name, path)?, value.to_vec());
+    item.filename = file_name(path);
+    Ok(item)
+}
+
+/// The index starts with INDEX_MAGIC followed by a version byte, and then
+/// lists entries. Each entry is prefixed by its little-endian u32 length, and
+/// is composed of fields encoded just like item fields.
+pub(crate) const INDEX_MAGIC: &[u8] = b"\xFFOXIDX";
+pub(crate) const INDEX_VERSION: u8 = 1;
+
+const IDX_GROUP: u8 = 1;
+const IDX_NAME: u8 = 2;
+const IDX_FILENAME: u8 = 3;
+const IDX_CONTENT_TYPE: u8 = 4;
+const IDX_PREVIEW: u8 = 5;
+const IDX_LENGTH: u8 = 6;
+const IDX_HASH: u8 = 7;
+const IDX_FILE_SIZE: u8 = 8;
+const IDX_FILE_MTIME: u8 = 9;
+
+/// IndexEntry is an item as listed in the index, along with the size and
+/// modification time (in nanoseconds since the Unix epoch) its file had when
+/// it was indexed.
+#[derive(Debug, Clone)]
+pub(crate) struct IndexEntry {
+    pub item: Item,
+    pub file_size: u64,
+    pub file_mtime: u64,
+}
+
+pub(crate) fn encode_index(entries: &[IndexEntry]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(INDEX_MAGIC.len() + 1 + entries.len() * 128);
+    buf.extend_from_slice(INDEX_MAGIC);
+    buf.push(INDEX_VERSION);
+    let mut entry = Vec::with_capacity(512);
+    for e in entries {
+        entry.clear();
+        push_field(&mut entry, IDX_GROUP, e.item.group.as_bytes());
+        push_field(&mut entry, IDX_NAME, e.item.name.as_bytes());
+        push_field(&mut entry, IDX_FILENAME, e.item.filename.as_bytes());
+        push_field(&mut entry, IDX_CONTENT_TYPE, e.item.meta.content_type.as_bytes());
+        push_field(&mut entry, IDX_PREVIEW, e.item.summary.preview.as_bytes());
+        push_field(&mut entry, IDX_LENGTH, &e.item.summary.length.to_le_bytes());
+        push_field(&mut entry, IDX_HASH, e.item.summary.hash.as_bytes());
+        push_field(&mut entry, IDX_FILE_SIZE, &e.file_size.to_le_bytes());
+        push_field(&mut entry, IDX_FILE_MTIME, &e.file_mtime.to_le_bytes());
+        buf.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&entry);
+    }
+    buf
+}
+
+fn decode_index_entry(data: &[u8], path: &Path) -> Result<IndexEntry> {
+    let mut item = Item::new("", "", "");
+    let (mut file_size, mut file_mtime) = (0, 0);
+    for field in fields(data, path) {
+        let (tag, field) = field?;
+        match tag {
+            IDX_GROUP => item.group = text(field, path)?,
+            IDX_NAME => item.name = text(field, path)?,
+            IDX_FILENAME => item.filename = text(field, path)?,
+            IDX_CONTENT_TYPE => item.meta.content_type = text(field, path)?,
+            IDX_PREVIEW => item.summary.preview = text(field, path)?,
+            IDX_LENGTH => item.summary.length = number(field, path)?,
+            IDX_HASH => item.summary.hash = text(field, path)?,
+            IDX_FILE_SIZE => file_size = number(field, path)?,
+            IDX_FILE_MTIME => file_mtime = number(field, path)?,
+            _ => {}
+        }
+    }
+    if item.filename.is_empty() {
+        return Err(corrupt(path, "entry without a filename"));
+    }
+    Ok(IndexEntry { item, file_size, file_mtime })
+}
+
+/// Decodes the index. Indexes written in other formats or versions are
+/// reported as errors, so they can be rebuilt.
+pub(crate) fn decode_index(bytes: &[u8], path: &Path) -> Result<Vec<IndexEntry>> {
+    if !bytes.starts_with(INDEX_MAGIC) || bytes.get(INDEX_MAGIC.len()) != Some(&INDEX_VERSION) {
+        return Err(corrupt(path, "unsupported index format"));
+    }
+    entries(&bytes[INDEX_MAGIC.len() + 1..], path)
+        .map(|e| e.and_then(|e| decode_index_entry(e, path)))
+        .collect()
+}
+
+/// The trigram index, kept in .trigrams next to the index, starts with
+/// TRIGRAMS_MAGIC followed by a version byte. Entries follow the layout of
+/// the index, each listing the trigrams found in the value of an item, as of
+/// when the value had the recorded hash.
+pub(crate) const TRIGRAMS_MAGIC: &[u8] = b"\xFFOXTRI";
+pub(crate) const TRIGRAMS_VERSION: u8 = 2;
+
+const TRI_FILENAME: u8 = 1;
+const TRI_HASH: u8 = 2;
+// Trigrams are packed into little-endian u64s, 21 bits per character.
+const TRI_TRIGRAMS: u8 = 3;
+
+/// TrigramEntry lists the trigrams found in the value of an item, sorted.
+#[derive(Debug, Clone)]
+pub(crate) struct TrigramEntry {
+    pub filename: String,
+    pub hash: String,
+    pub trigrams: Vec<u64>,
+}
+
+pub(crate) fn encode_trigrams(entries: &[TrigramEntry]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(TRIGRAMS_MAGIC.len() + 1 + entries.len() * 1024);
+    buf.extend_from_slice(TRIGRAMS_MAGIC);
+    buf.push(TRIGRAMS_VERSION);
+    let mut entry = Vec::with_capacity(1024);
+    for e in entries {
+        entry.clear();
+        push_field(&mut entry, TRI_FILENAME, e.filename.as_bytes());
+        push_field(&mut entry, TRI_HASH, e.hash.as_bytes());
+        let trigrams = e.trigrams.iter().flat_map(|t| t.to_le_bytes()).collect::<Vec<_>>();
+        push_field(&mut entry, TRI_TRIGRAMS, &trigrams);
+        buf.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&entry);
+    }
+    buf
+}
+
+fn decode_trigram_entry(data: &[u8], path: &Path) -> Result<TrigramEntry> {
+    let mut entry = TrigramEntry { filename: String::new(), hash: String::new(), trigrams: vec![] };
+    for field in fields(data, path) {
+        let (tag, field) = field?;
+        match tag {
+            TRI_FILENAME => entry.filename = text(field, path)?,
+            TRI_HASH => entry.hash = text(field, path)?,
+            TRI_TRIGRAMS => {
+                entry.trigrams = field.chunks(8)
+                    .map(|c| number(c, path))
+                    .collect::<Result<Vec<_>>>()?;
+            }
+            _ => {}
+        }
+    }
+    if entry.filename.is_empty() {
+        return Err(corrupt(path, "entry without a filename"));
+    }
+    Ok(entry)
+}
+
+/// Decodes the trigram index. Like the index, it can always be rebuilt, so
+/// other formats or versions are reported as errors.
+pub(crate) fn decode_trigrams(bytes: &[u8], path: &Path) -> Result<Vec<TrigramEntry>> {
+    if !bytes.starts_with(TRIGRAMS_MAGIC) || bytes.get(TRIGRAMS_MAGIC.len()) != Some(&TRIGRAMS_VERSION) {
+        return Err(corrupt(path, "unsupported trigram index format"));
+    }
+    entries(&bytes[TRIGRAMS_MAGIC.len() + 1..], path)
+        .map(|e| e.and_then(|e| decode_trigram_entry(e, path)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(item: &Item) -> Item {
+        let (decoded, format) = decode(&encode(item), Path::new("item")).unwrap();
+        assert_eq!(format, Format::Versioned(VERSION));
+        decoded
+    }
+
+    #[test]
+    fn text_values_round_trip() {
+        let mut item = Item::new("gif", "magic", "http://i.imgur.com/n5xR79B.gif\n");
+        item.meta.description = Some("a gif".to_string());
+        item.meta.tags = vec!["fun".to_string(), "web".to_string()];
+        item.meta.created = Some(1);
+        item.meta.updated = Some(2);
+        let decoded = round_trip(&item);
+        assert_eq!(decoded.group, "gif");
+        assert_eq!(decoded.name, "magic");
+        assert_eq!(decoded.value, item.value);
+        assert_eq!(decoded.meta, item.meta);
+        assert_eq!(decoded.summary, item.summary);
+    }
+
+    #[test]
+    fn binary_values_round_trip() {
+        let item = Item::new("keys", "blob", vec![0xFF, 0x00, 0x9F, b'O']);
+        assert_eq!(round_trip(&item).value, Value::Binary(vec![0xFF, 0x00, 0x9F, b'O']));
+
+        // Binary values that happen to be valid UTF-8 remain binary
+        let mut item = Item::new("keys", "ascii", "abc");
+        item.value = Value::Binary(b"abc".to_vec());
+        item.meta.content_type = "application/octet-stream".to_string();
+        assert_eq!(round_trip(&item).value, Value::Binary(b"abc".to_vec()));
+    }
+
+    #[test]
+    fn empty_values_round_trip() {
+        let decoded = round_trip(&Item::new("g", "n", ""));
+        assert_eq!(decoded.value, Value::Text(String::new()));
+        assert_eq!(decoded.summary.length, 0);
+    }
+
+    #[test]
+    fn legacy_items_are_decoded() {
+        let (item, format) = decode(b"gif\0magic\0http://x\0", Path::new("legacy")).unwrap();
+        assert_eq!(format, Format::Legacy);
+        assert_eq!((item.group.as_str(), item.name.as_str()), ("gif", "magic"));
+        assert_eq!(item.value, Value::Text("http://x".to_string()));
+        assert_eq!(item.filename, "legacy");
+
+        assert!(matches!(decode(b"gif\0magic", Path::new("legacy")), Err(Error::Corrupt { .. })));
+    }
+
+    #[test]
+    fn truncated_items_are_corrupt() {
+        let bytes = encode(&Item::new("g", "n", "value"));
+        // Cut inside the length of the first field, then inside its contents
+        for len in [MAGIC.len() + 3, MAGIC.len() + 7, bytes.len() - 1].iter() {
+            match decode(&bytes[..*len], Path::new("item")) {
+                Err(Error::Corrupt { .. }) => {}
+                other => panic!("decoding {} bytes gave {:?}", len, other),
+            }
+        }
+        assert!(matches!(decode(MAGIC, Path::new("item")), Err(Error::Corrupt { .. })));
+    }
+
+    #[test]
+    fn truncated_indexes_are_corrupt() {
+        let mut entry = IndexEntry { item: Item::new("g", "n", "v"), file_size: 1, file_mtime: 2 };
+        entry.item.filename = "f".to_string();
+        let bytes = encode_index(&[entry]);
+        assert_eq!(decode_index(&bytes, Path::new(".index")).unwrap()[0].item.filename, "f");
+        for len in [INDEX_MAGIC.len() + 3, bytes.len() - 1].iter() {
+            assert!(matches!(decode_index(&bytes[..*len], Path::new(".index")), Err(Error::Corrupt { .. })));
+        }
+    }
+}
@@ -1,195 +1,723 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::env;
-use std::io::{ErrorKind, BufReader, BufRead, Write};
+use std::io::Write;
 use std::fs::{File, OpenOptions};
+use std::time::UNIX_EPOCH;
 
-use sha1::{Sha1, Digest};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 
-use crate::entities;
-use crate::entities::Item;
-use crate::levenshtein;
-use crate::result::{Operation, Result, Error, OxResult, OxError};
+use crate::entities::{CorruptItem, Item, Reindex, Summary, Value};
+use crate::format;
+use crate::format::{Format, IndexEntry};
+use crate::matcher::Matcher;
+use crate::result::{Operation, Result, Error, IoContext};
 
-pub(crate) fn cache_path() -> PathBuf {
-    Path::new(&format!("{}/.oxio.cache", env::var("HOME").unwrap())).to_path_buf()
+fn non_empty_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.is_empty())
 }
 
-fn ensure_cache(path: &PathBuf) -> Operation {
+/// Resolves the default cache location. OXIO_HOME takes precedence, followed
+/// by an existing legacy ~/.oxio.cache directory, and then the XDG data
+/// directory ($XDG_DATA_HOME/oxio, or ~/.local/share/oxio).
+pub(crate) fn cache_path() -> Result<PathBuf> {
+    cache_path_from(non_empty_var)
+}
+
+// Resolves the default cache location from the variables returned by var,
+// which yields None for unset or empty ones.
+fn cache_path_from<F: Fn(&str) -> Option<String>>(var: F) -> Result<PathBuf> {
+    if let Some(path) = var("OXIO_HOME") {
+        return Ok(PathBuf::from(shellexpand::tilde(&path).to_string()));
+    }
+
+    let home = var("HOME").map(PathBuf::from);
+    if let Some(legacy) = home.as_ref().map(|h| h.join(".oxio.cache")) {
+        if legacy.exists() {
+            return Ok(legacy);
+        }
+    }
+
+    if let Some(data_home) = var("XDG_DATA_HOME") {
+        return Ok(Path::new(&data_home).join("oxio"));
+    }
+
+    match home {
+        Some(h) => Ok(h.join(".local").join("share").join("oxio")),
+        None => Err(Error::new("Could not determine where to keep the local cache. Please set OXIO_HOME or use --store.")),
+    }
+}
+
+/// Resolves the directory holding oxio's configuration: $XDG_CONFIG_HOME/oxio,
+/// or ~/.config/oxio.
+pub(crate) fn config_path() -> Result<PathBuf> {
+    if let Some(config_home) = non_empty_var("XDG_CONFIG_HOME") {
+        return Ok(Path::new(&config_home).join("oxio"));
+    }
+    match non_empty_var("HOME") {
+        Some(h) => Ok(Path::new(&h).join(".config").join("oxio")),
+        None => Err(Error::new("Could not determine where to keep oxio's configuration. Please set XDG_CONFIG_HOME.")),
+    }
+}
+
+/// Resolves the directory under which named stores are created when no
+/// explicit path is provided: $XDG_DATA_HOME/oxio-stores, or
+/// ~/.local/share/oxio-stores.
+pub(crate) fn named_stores_path() -> Result<PathBuf> {
+    if let Some(data_home) = non_empty_var("XDG_DATA_HOME") {
+        return Ok(Path::new(&data_home).join("oxio-stores"));
+    }
+    match non_empty_var("HOME") {
+        Some(h) => Ok(Path::new(&h).join(".local").join("share").join("oxio-stores")),
+        None => Err(Error::new("Could not determine where to create the store. Please provide a path.")),
+    }
+}
+
+pub(crate) fn ensure_cache(path: &Path) -> Operation {
     if path.exists() {
         return if path.is_dir() {
             Ok(())
         } else {
             Err(Error::new(&format!("{} already exists and is not a directory.",
-                                    path.to_str().unwrap())))
+                                    path.display())))
         };
     }
-    std::fs::create_dir(path).into_ox_result()
+    std::fs::create_dir_all(path).at(path)
 }
 
-fn read_component(buf: &mut BufReader<File>, path: &str) -> std::io::Result<String> {
-    let mut bytes: Vec<u8> = Vec::new();
-    if buf.read_until(0x00u8, &mut bytes)? == 0 {
-        return Err(std::io::Error::new(ErrorKind::UnexpectedEof,
-                                       format!("Invalid or corrupt entry at {}", path)));
-    }
-    if bytes.ends_with(&[0x00u8]) {
-        bytes.pop();
-    }
-    Ok(String::from_utf8(bytes)
-        .unwrap_or_else(|_| panic!("Invalid UTF8 value at {}", path)))
+pub(crate) fn read_item_with_format(path: &Path) -> Result<(Item, Format)> {
+    let bytes = std::fs::read(path).at(path)?;
+    format::decode(&bytes, path)
 }
 
-pub(crate) fn read_item(path: &PathBuf) -> Result<Item> {
-    let mut buf = BufReader::new(File::open(path)?);
-    let path_str = path.to_str().unwrap();
-    let group = read_component(&mut buf, path_str)?;
-    let name = read_component(&mut buf, path_str)?;
-    let value = read_component(&mut buf, path_str)?;
-    let filename = path.file_name().unwrap().to_str().unwrap().to_string();
-    Ok(entities::Item { group, name, value, filename })
+pub(crate) fn read_item(path: &Path) -> Result<Item> {
+    Ok(read_item_with_format(path)?.0)
 }
 
-fn read_items(items: &[PathBuf]) -> Result<Vec<Item>> {
+// Returns the size and modification time, in nanoseconds since the epoch,
+// recorded in the index to notice files changed behind its back.
+fn file_stamp(path: &Path) -> std::io::Result<(u64, u64)> {
+    stamp(&std::fs::metadata(path)?)
+}
+
+fn stamp(metadata: &std::fs::Metadata) -> std::io::Result<(u64, u64)> {
+    let mtime = metadata.modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    Ok((metadata.len(), mtime))
+}
+
+fn index_entry(path: &Path, mut item: Item) -> Result<IndexEntry> {
+    let (file_size, file_mtime) = file_stamp(path).at(path)?;
+    item.value = Value::default();
+    Ok(IndexEntry { item, file_size, file_mtime })
+}
+
+// Reads all provided items into index entries, setting aside the ones that
+// cannot be read.
+fn read_entries(items: &[PathBuf]) -> (Vec<IndexEntry>, Vec<CorruptItem>) {
     let mut ret = Vec::with_capacity(items.len());
+    let mut corrupt = vec![];
     for item in items {
-        ret.push(read_item(item)?)
+        match read_item(item).and_then(|i| index_entry(item, i)) {
+            Ok(e) => ret.push(e),
+            Err(error) => corrupt.push(CorruptItem {
+                filename: item.file_name().unwrap().to_string_lossy().to_string(),
+                error,
+            }),
+        }
     }
-    Ok(ret)
+    (ret, corrupt)
 }
 
-pub fn index() -> Result<usize> {
-    let path = cache_path();
-    ensure_cache(&path)?;
-
-    let files = std::fs::read_dir(&path)?
-        .filter(|i| i.is_ok())
-        .map(|e| e.unwrap())
-        .filter(|e| e.file_type().is_ok() && e.file_type().unwrap().is_file())
-        .filter(|f| f.file_name().to_str().unwrap().ne(".index"))
-        .filter(|f| f.file_name().to_str().unwrap().ne(".gitignore"))
-        .map(|f| f.path())
-        .collect::<Vec<_>>();
+/// Writes the provided contents to a path without ever exposing a partially
+/// written file. Contents are first written to a temporary file in the same
+/// directory, flushed to disk, and then renamed over the target.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Operation {
+    let dir = path.parent().unwrap();
+    let tmp_name: String = thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect();
+    let tmp_path = dir.join(format!(".tmp-{}", tmp_name));
 
-    let items = read_items(&files)?;
-    let mut index_size = 0;
-    for item in &items {
-        index_size += item.name.len() + item.group.len() + item.filename.len() + 3;
+    let result = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_path)
+        .and_then(|mut f| {
+            f.write_all(bytes)?;
+            f.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(e).at(path);
     }
-    let mut buf: Vec<u8> = Vec::with_capacity(index_size);
-    for item in &items {
-        buf.append(&mut item.group.clone().into_bytes());
-        buf.push(0x00u8);
-        buf.append(&mut item.name.clone().into_bytes());
-        buf.push(0x00u8);
-        buf.append(&mut item.filename.clone().into_bytes());
-        buf.push(0x00u8)
+
+    // Persist the rename itself. Directories cannot be opened for syncing on
+    // every platform, so this is done on a best-effort basis.
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
     }
+    Ok(())
+}
 
-    OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(path.join(".index"))?
-        .write_all(buf.as_slice())?;
-
-    Ok(items.len())
-}
-
-pub fn get_all_items() -> Result<Vec<Item>> {
-    let index_path = cache_path().join(".index");
-    if !index_path.exists() {
-        return Ok(vec![]);
-    }
-    let index_path_str = index_path.to_str().unwrap();
-    let mut buf = BufReader::new(File::open(&index_path)?);
-    let mut items = Vec::new();
-    loop {
-        let group = match read_component(&mut buf, &index_path_str) {
-            Ok(v) => v,
-            Err(e) => if e.kind() == ErrorKind::UnexpectedEof {
-                break;
-            } else {
-                return Err(e.as_ox_error());
-            },
+fn write_index(cache: &Path, entries: &[IndexEntry]) -> Operation {
+    write_atomic(&cache.join(".index"), &format::encode_index(entries))
+}
+
+// Items are kept in files named after hashes, so anything starting with a dot
+// (.index, .gitignore, temporary files) is not an item.
+fn is_item_file(name: &str) -> bool {
+    !name.starts_with('.')
+}
+
+// Returns when an item was last updated. The index does not record it, so
+// entries taken from the index have it read from their file.
+fn updated_at(cache: &Path, entry: &IndexEntry) -> Option<u64> {
+    entry.item.meta.updated
+        .or_else(|| read_item(&cache.join(&entry.item.filename)).ok()?.meta.updated)
+}
+
+// An interrupted replacement may leave both the old and new files of an item
+// behind. When both carry an update time, only the newest is kept.
+fn drop_superseded(cache: &Path, entries: Vec<IndexEntry>) -> Vec<IndexEntry> {
+    let mut kept: Vec<IndexEntry> = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let item = &entry.item;
+        let existing = kept.iter()
+            .position(|k| k.item.group == item.group && k.item.name == item.name);
+        let pos = match existing {
+            None => {
+                kept.push(entry);
+                continue;
+            }
+            Some(p) => p,
+        };
+        let (old, new) = match (updated_at(cache, &kept[pos]), updated_at(cache, &entry)) {
+            (Some(a), Some(b)) if a < b => (kept[pos].clone(), entry),
+            (Some(a), Some(b)) if b < a => (entry, kept[pos].clone()),
+            _ => {
+                kept.push(entry);
+                continue;
+            }
         };
-        let name = read_component(&mut buf, &index_path_str)?;
-        let filename = read_component(&mut buf, &index_path_str)?;
-        items.push(Item { group, name, filename, value: "".to_string() })
+        let _ = std::fs::remove_file(cache.join(&old.item.filename));
+        kept[pos] = new;
     }
-    Ok(items)
+    kept
 }
 
-pub fn find_item(name: &str) -> Result<Option<Item>> {
-    let mut items: Vec<(usize, Item)> = get_all_items()?
-        .into_iter()
-        .map(|i| (levenshtein::distance(&i.name, name), i))
-        .collect();
+fn item_files(cache: &Path) -> Result<Vec<PathBuf>> {
+    Ok(item_stamps(cache)?.into_iter().map(|(path, _)| path).collect())
+}
 
-    items.sort_by(|(a, _), (b, _)| a.cmp(b));
-    if items.is_empty() {
-        return Ok(None);
+// Returns the item files of a cache along with their size and modification
+// time, taken from the directory listing without reading the files.
+fn item_stamps(cache: &Path) -> Result<Vec<(PathBuf, (u64, u64))>> {
+    Ok(std::fs::read_dir(cache)?
+        .filter_map(|i| i.ok())
+        .filter(|f| is_item_file(&f.file_name().to_string_lossy()))
+        .filter_map(|f| match f.metadata() {
+            Ok(m) if m.is_file() => stamp(&m).ok().map(|s| (f.path(), s)),
+            _ => None,
+        })
+        .collect::<Vec<_>>())
+}
+
+/// Rebuilds the index of a cache from scratch. Items that cannot be read are
+/// left out of the index, and reported back along with their errors.
+pub(crate) fn index(cache: &Path) -> Result<Reindex> {
+    ensure_cache(cache)?;
+    let (entries, corrupt) = rebuild_index(cache)?;
+    Ok(Reindex { items: entries.len(), corrupt })
+}
+
+fn rebuild_index(cache: &Path) -> Result<(Vec<IndexEntry>, Vec<CorruptItem>)> {
+    let (entries, corrupt) = read_entries(&item_files(cache)?);
+    let entries = drop_superseded(cache, entries);
+    write_index(cache, &entries)?;
+    Ok((entries, corrupt))
+}
+
+// Brings the index up to date by dropping entries whose files are gone, and
+// reading only the files that are new or whose size or modification time
+// differ from what the index recorded. The index is left untouched when
+// nothing changed. Files that cannot be read are returned along with their
+// errors; they stay out of the index, so they are read again next time.
+fn refresh_index(cache: &Path, mut entries: Vec<IndexEntry>) -> Result<(Vec<IndexEntry>, Vec<CorruptItem>)> {
+    let mut files = item_stamps(cache)?.into_iter().collect::<HashMap<_, _>>();
+    let count = entries.len();
+    entries.retain(|e| {
+        let path = cache.join(&e.item.filename);
+        let fresh = files.get(&path) == Some(&(e.file_size, e.file_mtime));
+        if fresh {
+            files.remove(&path);
+        }
+        fresh
+    });
+
+    let (new_entries, corrupt) = read_entries(&files.into_keys().collect::<Vec<_>>());
+    if entries.len() == count && new_entries.is_empty() {
+        return Ok((entries, corrupt));
     }
+    entries.extend(new_entries);
+    let entries = drop_superseded(cache, entries);
+
+    // Refreshing may happen while only holding a shared lock, in which case
+    // every holder computes the same index. Failing to persist it, such as on
+    // a read-only cache, only means the next invocation refreshes it again.
+    let _ = write_index(cache, &entries);
+    Ok((entries, corrupt))
+}
 
-    let (distance, first_item) = &items[0];
-    if *distance > 2 {
-        Ok(None)
-    } else {
-        Ok(Some(first_item.clone()))
+/// Updates the index of a cache after the provided files were changed,
+/// reading only those, and returns how many items it lists. A missing or
+/// corrupt index is rebuilt instead.
+pub(crate) fn refresh(cache: &Path, changed: &[String]) -> Result<usize> {
+    let mut entries = match read_index(cache) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(rebuild_index(cache)?.0.len()),
+    };
+    entries.retain(|e| !changed.contains(&e.item.filename));
+    let paths = changed.iter()
+        .filter(|f| is_item_file(f))
+        .map(|f| cache.join(f))
+        .filter(|p| p.is_file())
+        .collect::<Vec<_>>();
+    entries.extend(read_entries(&paths).0);
+    let entries = drop_superseded(cache, entries);
+    write_index(cache, &entries)?;
+    Ok(entries.len())
+}
+
+fn read_index(cache: &Path) -> Result<Vec<IndexEntry>> {
+    let path = cache.join(".index");
+    format::decode_index(&std::fs::read(&path).at(&path)?, &path)
+}
+
+// Returns all entries of the index of a cache, along with the files that
+// could not be read. A stale index is refreshed incrementally, while a
+// missing, corrupt or outdated one is rebuilt.
+fn read_all_entries(cache: &Path) -> Result<(Vec<IndexEntry>, Vec<CorruptItem>)> {
+    if !cache.exists() {
+        return Ok((vec![], vec![]));
+    }
+    match read_index(cache) {
+        Ok(entries) => refresh_index(cache, entries),
+        Err(_) => rebuild_index(cache),
     }
 }
 
-pub fn get_item(group: &str, name: &str) -> Result<Option<Item>> {
-    Ok(get_all_items()?
+fn get_all_entries(cache: &Path) -> Result<Vec<IndexEntry>> {
+    Ok(read_all_entries(cache)?.0)
+}
+
+/// Returns all items listed in the index of a cache, along with a summary of
+/// their values. Values themselves are not loaded.
+pub(crate) fn get_all_items(cache: &Path) -> Result<Vec<Item>> {
+    Ok(get_all_entries(cache)?.into_iter().map(|e| e.item).collect())
+}
+
+/// Returns the files of a cache that cannot be read as items, and are
+/// therefore left out of its index.
+pub(crate) fn corrupt_items(cache: &Path) -> Result<Vec<CorruptItem>> {
+    Ok(read_all_entries(cache)?.1)
+}
+
+/// Returns items matching a query along with their score, best first.
+pub(crate) fn search(cache: &Path, matcher: &Matcher, query: &str) -> Result<Vec<(f64, Item)>> {
+    let mut items: Vec<(f64, Item)> = get_all_items(cache)?
+        .into_iter()
+        .filter_map(|i| matcher.score(query, &i).map(|s| (s, i)))
+        .collect();
+    sort_by_score(&mut items);
+    Ok(items)
+}
+
+/// Sorts scored entries, best first, keeping the order of equal ones.
+pub(crate) fn sort_by_score<T>(entries: &mut [(f64, T)]) {
+    entries.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+}
+
+/// Returns the groups preferred when several items match a lookup equally
+/// well, as listed in OXIO_GROUP_PRIORITY, separated by commas.
+pub(crate) fn group_priority() -> Vec<String> {
+    non_empty_var("OXIO_GROUP_PRIORITY")
+        .map(|v| parse_group_priority(&v))
+        .unwrap_or_default()
+}
+
+fn parse_group_priority(v: &str) -> Vec<String> {
+    v.split(',').map(|g| g.trim().to_string()).filter(|g| !g.is_empty()).collect()
+}
+
+/// Keeps the best scored of the provided candidates, which must be sorted
+/// by score. When several remain, only the ones in the group listed first
+/// in OXIO_GROUP_PRIORITY are kept, if any of their groups is listed.
+pub(crate) fn best_matches<T, F>(candidates: Vec<(f64, T)>, group: F) -> Vec<T>
+    where F: Fn(&T) -> &str {
+    best_matches_in(candidates, &group_priority(), group)
+}
+
+fn best_matches_in<T, F>(candidates: Vec<(f64, T)>, priority: &[String], group: F) -> Vec<T>
+    where F: Fn(&T) -> &str {
+    let best = match candidates.first() {
+        None => return vec![],
+        Some((s, _)) => *s,
+    };
+    let tied: Vec<T> = candidates.into_iter()
+        .take_while(|(s, _)| s.total_cmp(&best).is_eq())
+        .map(|(_, c)| c)
+        .collect();
+
+    let rank = |c: &T| priority.iter().position(|g| g == group(c)).unwrap_or(priority.len());
+    let top = tied.iter().map(rank).min().unwrap_or(0);
+    tied.into_iter().filter(|c| rank(c) == top).collect()
+}
+
+pub(crate) fn get_item(cache: &Path, group: &str, name: &str) -> Result<Option<Item>> {
+    Ok(get_all_items(cache)?
         .into_iter()
         .find(|i| i.name.eq(name) && i.group.eq(group)))
 }
 
-pub fn create_item(group: &str, name: &str, value: &mut String) -> Operation {
-    let cache_path = cache_path();
-    ensure_cache(&cache_path)?;
+/// Writes an item, replacing any item with the same group and name. Items are
+/// kept in files named after their ID, so edits replace the file in place.
+/// Items written by older versions may still live under another name, in
+/// which case the previous file is only removed once the index points to
+/// the new one, so readers never observe a missing item.
+pub(crate) fn create_item(cache: &Path, item: &Item) -> Operation {
+    ensure_cache(cache)?;
+
+    let mut item = item.clone();
+    item.name = item.name.to_lowercase();
+    let now = format::now();
+    item.meta.updated = Some(now);
+    if item.meta.created.is_none() {
+        item.meta.created = Some(now);
+    }
 
-    // Ensure index is updated
-    index()?;
+    let mut entries = get_all_entries(cache)?;
+    let previous = entries.iter()
+        .position(|e| e.item.group == item.group && e.item.name == item.name);
 
-    // Delete if exists
-    if let Some(mut it) = get_item(group, name)? {
-        it.delete()?;
+    // Keep the creation date of the item being replaced
+    if let Some(pos) = previous {
+        if let Ok(existing) = read_item(&cache.join(&entries[pos].item.filename)) {
+            item.meta.created = existing.meta.created.or(item.meta.created);
+        }
     }
 
-    let name = name.to_lowercase();
-    let mut buf = Vec::with_capacity(group.len() + name.len() + value.len() + 2);
-    buf.append(group.as_bytes().to_vec().as_mut());
-    buf.push(0x00u8);
-    buf.append(name.as_bytes().to_vec().as_mut());
-    buf.push(0x00u8);
-    buf.append(value.as_bytes().to_vec().as_mut());
-    let bytes = buf.as_slice();
+    item.filename = item.id();
+    item.summary = Summary::of(&item.value);
+    let path = cache.join(&item.filename);
+    write_atomic(&path, &format::encode(&item))?;
 
-    let mut hasher = Sha1::new();
-    hasher.update(bytes.as_ref());
+    let entry = index_entry(&path, item.clone())?;
+    let replaced = match previous {
+        Some(pos) => Some(std::mem::replace(&mut entries[pos], entry)),
+        None => {
+            entries.push(entry);
+            None
+        }
+    };
+    write_index(cache, &entries)?;
 
-    let hash_digest = format!("{:x}", hasher.finalize());
-    OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(cache_path.join(hash_digest))?
-        .write_all(bytes)?;
-    // Reindex
-    index()?;
+    if let Some(old) = replaced {
+        if old.item.filename != item.filename {
+            old.item.delete(cache)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes items from a cache. The index is updated before files are
+/// removed, so it never refers to a missing file.
+pub(crate) fn remove_items(cache: &Path, removed: &[Item]) -> Operation {
+    let entries = get_all_entries(cache)?
+        .into_iter()
+        .filter(|e| !removed.iter().any(|r| r.filename == e.item.filename))
+        .collect::<Vec<_>>();
+    write_index(cache, &entries)?;
+    for item in removed {
+        item.delete(cache)?;
+    }
     Ok(())
 }
 
-pub fn get_all_group(group: &str) -> Result<Vec<Item>> {
-    Ok(get_all_items()?
+/// Rewrites all items stored in the legacy format using the current format,
+/// and renames files not yet named after their item's ID, returning how many
+/// items were migrated. Legacy items did not track timestamps, so their file
+/// modification time is used instead.
+pub(crate) fn migrate(cache: &Path) -> Result<usize> {
+    let mut migrated = 0;
+    for entry in get_all_items(cache)? {
+        let path = cache.join(&entry.filename);
+        let (mut item, fmt) = read_item_with_format(&path)?;
+        let id = item.id();
+        if fmt != Format::Legacy && entry.filename == id {
+            continue;
+        }
+        if fmt == Format::Legacy {
+            if item.value.is_binary() {
+                item.meta.content_type = item.value.guess_content_type().to_string();
+            }
+            let modified = std::fs::metadata(&path).at(&path)?
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs());
+            item.meta.created = modified;
+            item.meta.updated = modified;
+        }
+        write_atomic(&cache.join(&id), &format::encode(&item))?;
+        if entry.filename != id {
+            std::fs::remove_file(&path).at(&path)?;
+        }
+        migrated += 1;
+    }
+    if migrated > 0 {
+        rebuild_index(cache)?;
+    }
+    Ok(migrated)
+}
+
+pub(crate) fn get_all_group(cache: &Path, group: &str) -> Result<Vec<Item>> {
+    Ok(get_all_items(cache)?
         .into_iter()
         .filter(|i| i.group.eq(group))
         .collect::<Vec<_>>())
 }
 
+// Names are only stored within items and the index, never used as paths, so
+// anything printable goes. Names matching commands are told apart by the CLI.
 pub fn is_valid_name(name: &str) -> bool {
-    let reserved_names = ["all", "rm-group", "rm-item", "sync", "reindex", "help", "edit"];
-    !reserved_names.contains(&name)
+    !name.is_empty() && !name.chars().any(|c| c.is_control())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // TempCache is a cache in a temporary directory, removed once dropped.
+    struct TempCache(PathBuf);
+
+    impl TempCache {
+        fn new() -> TempCache {
+            let name: String = thread_rng().sample_iter(&Alphanumeric).take(12).map(char::from).collect();
+            let dir = env::temp_dir().join(format!("oxio-test-{}", name));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempCache(dir)
+        }
+
+        // Writes an item to a file, as if changed behind oxio's back.
+        fn write(&self, filename: &str, item: &Item) -> PathBuf {
+            let path = self.0.join(filename);
+            std::fs::write(&path, format::encode(item)).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn values(cache: &Path) -> Vec<String> {
+        let mut values = get_all_items(cache).unwrap().into_iter()
+            .map(|i| format!("{}/{}={}", i.group, i.name, i.summary.preview))
+            .collect::<Vec<_>>();
+        values.sort();
+        values
+    }
+
+    fn set_mtime(path: &Path, mtime: std::time::SystemTime) {
+        File::options().write(true).open(path).unwrap().set_modified(mtime).unwrap();
+    }
+
+    fn cache_path_with(vars: &[(&str, &Path)]) -> Option<PathBuf> {
+        let vars = vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
+            .collect::<HashMap<_, _>>();
+        cache_path_from(|name| vars.get(name).cloned()).ok()
+    }
+
+    #[test]
+    fn cache_locations_are_picked_in_order() {
+        let c = TempCache::new();
+        let home = c.0.join("home");
+        let explicit = c.0.join("explicit");
+        let data = c.0.join("data");
+        let all = [("OXIO_HOME", explicit.as_path()), ("HOME", &home), ("XDG_DATA_HOME", &data)];
+
+        assert_eq!(cache_path_with(&all), Some(explicit.clone()));
+        assert_eq!(cache_path_with(&all[1..]), Some(data.join("oxio")));
+        assert_eq!(cache_path_with(&all[1..2]), Some(home.join(".local/share/oxio")));
+        assert_eq!(cache_path_with(&all[2..]), Some(data.join("oxio")));
+        assert_eq!(cache_path_with(&[]), None);
+
+        // The legacy cache is used as long as it exists, unless OXIO_HOME is set
+        std::fs::create_dir_all(home.join(".oxio.cache")).unwrap();
+        assert_eq!(cache_path_with(&all[1..]), Some(home.join(".oxio.cache")));
+        assert_eq!(cache_path_with(&all), Some(explicit));
+    }
+
+    #[test]
+    fn files_rewritten_with_the_same_mtime_are_reindexed() {
+        let c = TempCache::new();
+        let item = Item::new("gif", "magic", "v1");
+        let path = c.write(&item.id(), &item);
+        assert_eq!(values(&c.0), vec!["gif/magic=v1"]);
+
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        c.write(&item.id(), &Item::new("gif", "magic", "longer"));
+        set_mtime(&path, mtime);
+        assert_eq!(values(&c.0), vec!["gif/magic=longer"]);
+    }
+
+    #[test]
+    fn files_rewritten_with_the_same_size_are_reindexed() {
+        let c = TempCache::new();
+        let item = Item::new("gif", "magic", "v1");
+        let path = c.write(&item.id(), &item);
+        assert_eq!(values(&c.0), vec!["gif/magic=v1"]);
+
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        c.write(&item.id(), &Item::new("gif", "magic", "v2"));
+        set_mtime(&path, mtime + Duration::from_secs(1));
+        assert_eq!(values(&c.0), vec!["gif/magic=v2"]);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(values(&c.0).is_empty());
+    }
+
+    #[test]
+    fn newer_copies_supersede_older_ones() {
+        let c = TempCache::new();
+        let mut old = Item::new("gif", "magic", "old");
+        old.meta.updated = Some(100);
+        let mut new = Item::new("gif", "magic", "new");
+        new.meta.updated = Some(200);
+        // Either copy may be listed first
+        c.write("c0ffee", &new);
+        c.write(&old.id(), &old);
+        assert_eq!(values(&c.0), vec!["gif/magic=new"]);
+        assert!(c.0.join("c0ffee").exists());
+        assert!(!c.0.join(old.id()).exists());
+
+        let mut newer = Item::new("gif", "magic", "newer");
+        newer.meta.updated = Some(300);
+        c.write(&old.id(), &newer);
+        assert_eq!(values(&c.0), vec!["gif/magic=newer"]);
+        assert!(!c.0.join("c0ffee").exists());
+    }
+
+    #[test]
+    fn unreadable_files_are_reported_until_fixed() {
+        let c = TempCache::new();
+        c.write("c0ffee", &Item::new("gif", "magic", "v1"));
+        std::fs::write(c.0.join("bad"), b"\xFFOXIO\x09").unwrap();
+        for _ in 0..2 {
+            let corrupt = corrupt_items(&c.0).unwrap();
+            assert_eq!(corrupt.iter().map(|i| i.filename.as_str()).collect::<Vec<_>>(), vec!["bad"]);
+            assert_eq!(values(&c.0), vec!["gif/magic=v1"]);
+        }
+        std::fs::remove_file(c.0.join("bad")).unwrap();
+        assert!(corrupt_items(&c.0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn legacy_items_are_migrated() {
+        let c = TempCache::new();
+        std::fs::write(c.0.join("c0ffee"), b"gif\0magic\0http://i.imgur.com/n5xR79B.gif").unwrap();
+        std::fs::write(c.0.join("beef"), b"img\0logo\0\x89PNG\r\n\x1a\n\x00\xff").unwrap();
+        let current = Item::new("notes", "todo", "buy milk");
+        c.write(&current.id(), &current);
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        set_mtime(&c.0.join("c0ffee"), mtime);
+        assert_eq!(values(&c.0).len(), 3);
+
+        assert_eq!(migrate(&c.0).unwrap(), 2);
+        assert!(!c.0.join("c0ffee").exists());
+        assert!(!c.0.join("beef").exists());
+        let magic = Item::new("gif", "magic", "");
+        let (item, fmt) = read_item_with_format(&c.0.join(magic.id())).unwrap();
+        assert_ne!(fmt, Format::Legacy);
+        assert_eq!(item.value.to_string(), "http://i.imgur.com/n5xR79B.gif");
+        assert_eq!((item.meta.created, item.meta.updated), (Some(1_600_000_000), Some(1_600_000_000)));
+        let (logo, _) = read_item_with_format(&c.0.join(Item::new("img", "logo", "").id())).unwrap();
+        assert_eq!(logo.meta.content_type, "image/png");
+        assert_eq!(logo.value.as_bytes(), b"\x89PNG\r\n\x1a\n\x00\xff");
+
+        assert_eq!(migrate(&c.0).unwrap(), 0);
+        assert_eq!(values(&c.0), vec!["gif/magic=http://i.imgur.com/n5xR79B.gif", "img/logo=", "notes/todo=buy milk"]);
+    }
+
+    fn filenames(cache: &Path) -> Vec<String> {
+        let mut names = item_files(cache).unwrap().into_iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn item_ids_survive_edits() {
+        let c = TempCache::new();
+        let item = Item::new("gif", "magic", "v1");
+        let id = item.id();
+        assert_eq!(id, Item::new("gif", "magic", "other value").id());
+        assert_ne!(id, Item::new("gif", "other", "v1").id());
+        assert_ne!(Item::new("ab", "c", "").id(), Item::new("a", "bc", "").id());
+
+        create_item(&c.0, &item).unwrap();
+        assert_eq!(filenames(&c.0), vec![id.clone()]);
+        create_item(&c.0, &Item::new("gif", "magic", "v2")).unwrap();
+        assert_eq!(filenames(&c.0), vec![id.clone()]);
+        let stored = get_all_items(&c.0).unwrap();
+        assert_eq!((stored[0].filename.as_str(), stored[0].id()), (id.as_str(), id.clone()));
+        assert_eq!(read_item(&c.0.join(&id)).unwrap().value.to_string(), "v2");
+    }
+
+    #[test]
+    fn edits_move_items_to_their_id() {
+        let c = TempCache::new();
+        std::fs::write(c.0.join("c0ffee"), b"gif\0magic\0v1").unwrap();
+        assert_eq!(values(&c.0), vec!["gif/magic=v1"]);
+
+        let item = Item::new("gif", "magic", "v2");
+        create_item(&c.0, &item).unwrap();
+        assert_eq!(filenames(&c.0), vec![item.id()]);
+        assert_eq!(values(&c.0), vec!["gif/magic=v2"]);
+    }
+
+    // Picks the best of candidates given as (score, "group/name").
+    fn best(candidates: &[(f64, &'static str)], priority: &str) -> Vec<&'static str> {
+        fn group<'a>(c: &'a &str) -> &'a str {
+            c.split('/').next().unwrap()
+        }
+        best_matches_in(candidates.to_vec(), &parse_group_priority(priority), group)
+    }
+
+    #[test]
+    fn group_priority_settles_ties() {
+        let tied = [(0.9, "gif/magic"), (0.9, "words/magic"), (0.9, "png/magic"), (0.5, "gif/magenta")];
+        assert_eq!(best(&tied, ""), vec!["gif/magic", "words/magic", "png/magic"]);
+        assert_eq!(best(&tied, "words"), vec!["words/magic"]);
+        assert_eq!(best(&tied, " png , words"), vec!["png/magic"]);
+        assert_eq!(best(&tied, "txt,,words,gif"), vec!["words/magic"]);
+        // Groups not listed are left as they are
+        assert_eq!(best(&tied, "txt"), vec!["gif/magic", "words/magic", "png/magic"]);
+        // Only ties are settled, so better matches in other groups win
+        assert_eq!(best(&[(1.0, "gif/magic"), (0.9, "words/magic")], "words"), vec!["gif/magic"]);
+        assert!(best(&[], "words").is_empty());
+        assert_eq!(parse_group_priority(" a, ,b ,"), vec!["a", "b"]);
+    }
 }
@@ -0,0 +1,235 @@
+//! Full-text search over item values.
+//!
+//! Values are matched line by line against a regular expression. To avoid
+//! reading every item, a trigram index kept in .trigrams lists the trigrams
+//! (sequences of three characters, case folded the way regexes ignoring case
+//! compare them) found in each value. Only
+//! values containing all trigrams of the literal text a pattern requires are
+//! read and matched. The trigram index is brought up to date as part of each
+//! search, using the value hashes kept in the index to tell which entries
+//! are stale.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+pub use regex::Regex;
+use regex_syntax::hir::{ClassUnicode, ClassUnicodeRange, Hir, HirKind};
+
+use crate::entities::{Item, Value};
+use crate::format;
+use crate::format::TrigramEntry;
+use crate::fs;
+use crate::result::Result;
+
+pub(crate) const TRIGRAMS_FILE: &str = ".trigrams";
+
+/// LineMatch is a line of a value matching a pattern.
+#[derive(Debug, Clone)]
+pub struct LineMatch {
+    /// Number of the line, starting at 1.
+    pub number: usize,
+    pub text: String,
+    /// Byte ranges of the text matching the pattern.
+    pub ranges: Vec<(usize, usize)>,
+}
+
+/// GrepMatch is an item matching a pattern. Its value is not loaded.
+#[derive(Debug, Clone)]
+pub struct GrepMatch {
+    pub item: Item,
+    /// Whether the pattern matched the `group/name` path of the item, when
+    /// paths were searched.
+    pub path_matched: bool,
+    /// Lines of the value matching the pattern.
+    pub lines: Vec<LineMatch>,
+}
+
+/// Returns items whose text values, or optionally `group/name` paths, match
+/// a pattern, in index order.
+pub(crate) fn grep(cache: &Path, pattern: &Regex, paths: bool) -> Result<Vec<GrepMatch>> {
+    let items = fs::get_all_items(cache)?;
+    let index_path = cache.join(TRIGRAMS_FILE);
+    let mut index: HashMap<String, TrigramEntry> = std::fs::read(&index_path).ok()
+        .and_then(|b| format::decode_trigrams(&b, &index_path).ok())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|e| (e.filename.clone(), e))
+        .collect();
+    let required = required_trigrams(pattern.as_str());
+
+    let mut changed = false;
+    let mut kept = Vec::with_capacity(items.len());
+    let mut matches = vec![];
+    for item in items {
+        let path = cache.join(&item.filename);
+        // Values read to index them are kept, so they are not read twice
+        let mut value = None;
+        let entry = match index.remove(&item.filename) {
+            Some(e) if e.hash == item.summary.hash => e,
+            _ => {
+                changed = true;
+                let v = match fs::read_item(&path) {
+                    Ok(i) => i.value,
+                    // Unreadable items are reported by reindex instead
+                    Err(_) => continue,
+                };
+                let trigrams = match &v {
+                    Value::Text(s) => trigrams(s),
+                    Value::Binary(_) => vec![],
+                };
+                value = Some(v);
+                TrigramEntry { filename: item.filename.clone(), hash: item.summary.hash.clone(), trigrams }
+            }
+        };
+
+        let candidate = required.iter().all(|t| entry.trigrams.binary_search(t).is_ok());
+        kept.push(entry);
+
+        let mut lines = vec![];
+        if candidate {
+            let value = match value {
+                Some(v) => v,
+                None => match fs::read_item(&path) {
+                    Ok(i) => i.value,
+                    Err(_) => continue,
+                },
+            };
+            if let Value::Text(s) = value {
+                lines = match_lines(pattern, &s);
+            }
+        }
+
+        let path_matched = paths && pattern.is_match(&format!("{}/{}", item.group, item.name));
+        if path_matched || !lines.is_empty() {
+            matches.push(GrepMatch { item, path_matched, lines });
+        }
+    }
+
+    // Entries of removed items are dropped. As with the index, failing to
+    // persist the trigram index only means the next search updates it again.
+    if changed || !index.is_empty() {
+        let _ = fs::write_atomic(&index_path, &format::encode_trigrams(&kept));
+    }
+    Ok(matches)
+}
+
+fn match_lines(pattern: &Regex, value: &str) -> Vec<LineMatch> {
+    value.lines()
+        .enumerate()
+        .filter(|(_, line)| pattern.is_match(line))
+        .map(|(n, line)| LineMatch {
+            number: n + 1,
+            text: line.to_string(),
+            ranges: pattern.find_iter(line)
+                .filter(|m| !m.is_empty())
+                .map(|m| (m.start(), m.end()))
+                .collect(),
+        })
+        .collect()
+}
+
+// Returns the sorted, distinct trigrams of a text, ignoring case.
+fn trigrams(text: &str) -> Vec<u64> {
+    let mut folded = HashMap::new();
+    let chars = text.chars()
+        .map(|c| *folded.entry(c).or_insert_with(|| fold(c)))
+        .collect::<Vec<_>>();
+    let mut trigrams = chars.windows(3)
+        .map(|w| (w[0] as u64) << 42 | (w[1] as u64) << 21 | w[2] as u64)
+        .collect::<Vec<_>>();
+    trigrams.sort_unstable();
+    trigrams.dedup();
+    trigrams
+}
+
+// Maps a character to the same representative as every character it matches
+// when ignoring case. Regexes follow Unicode simple case folding, under
+// which `ſ` matches `s` and the Kelvin sign matches `k`, so lowercasing is
+// not enough: the representative is the lowest character of the folded
+// class, which is the uppercase letter for ASCII.
+fn fold(c: char) -> char {
+    if c.is_ascii() {
+        return c.to_ascii_uppercase();
+    }
+    let mut class = ClassUnicode::new([ClassUnicodeRange::new(c, c)]);
+    match class.try_case_fold_simple() {
+        Ok(()) => class.ranges().first().map_or(c, |r| r.start()),
+        Err(_) => c,
+    }
+}
+
+// Returns trigrams any text matching the pattern must contain, ignoring
+// case. Patterns are parsed case-sensitively, since case-insensitive
+// literals are parsed as classes, which yield no trigrams; matching the
+// case folded trigrams of the index is correct either way.
+fn required_trigrams(pattern: &str) -> Vec<u64> {
+    let hir = match regex_syntax::Parser::new().parse(pattern) {
+        Ok(h) => h,
+        Err(_) => return vec![],
+    };
+    let mut runs = vec![];
+    let mut current = String::new();
+    literal_runs(&hir, &mut runs, &mut current);
+    runs.push(current);
+    let mut required = runs.iter().flat_map(|r| trigrams(r)).collect::<Vec<_>>();
+    required.sort_unstable();
+    required.dedup();
+    required
+}
+
+// Collects runs of literal text every match of an expression contains.
+// Anything that may vary, such as classes, alternations and optional
+// parts, ends the current run.
+fn literal_runs(hir: &Hir, runs: &mut Vec<String>, current: &mut String) {
+    match hir.kind() {
+        HirKind::Literal(lit) => current.push_str(&String::from_utf8_lossy(&lit.0)),
+        HirKind::Capture(c) => literal_runs(&c.sub, runs, current),
+        HirKind::Concat(subs) => {
+            for sub in subs {
+                literal_runs(sub, runs, current);
+            }
+        }
+        // Assertions match no text, so literals around them are adjacent
+        HirKind::Empty | HirKind::Look(_) => {}
+        HirKind::Repetition(r) if r.min > 0 => {
+            runs.push(std::mem::take(current));
+            literal_runs(&r.sub, runs, current);
+            runs.push(std::mem::take(current));
+        }
+        _ => runs.push(std::mem::take(current)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether pruning keeps a value the pattern matches, ignoring case.
+    fn kept(pattern: &str, value: &str) -> bool {
+        let index = trigrams(value);
+        required_trigrams(pattern).iter().all(|t| index.binary_search(t).is_ok())
+    }
+
+    #[test]
+    fn pruning_follows_case_folding() {
+        let cases = [
+            ("password", "PassWord"),
+            ("password", "paſſword"),
+            ("PAſſWORD", "password"),
+            ("kelvin", "\u{212A}elvin"),
+            ("straße", "STRAẞE"),
+        ];
+        for (pattern, value) in cases.iter() {
+            let re = regex::RegexBuilder::new(pattern).case_insensitive(true).build().unwrap();
+            assert!(re.is_match(value), "{} does not match {}", pattern, value);
+            assert!(kept(pattern, value), "{} was pruned for {}", value, pattern);
+        }
+    }
+
+    #[test]
+    fn values_missing_literals_are_pruned() {
+        assert!(!kept("password", "passport"));
+        assert!(!kept("a.*secret", "a secure note"));
+        assert!(kept("a.*secret", "a secret"));
+    }
+}
@@ -0,0 +1,28 @@
+//! Oxio is a nimble snippet manager. This crate exposes the snippet store
+//! used by the `oxio` binary, so it can be embedded in other tools.
+//!
+//! ```no_run
+//! let store = oxio::Store::open_default()?;
+//! store.set("gif", "magic", "http://i.imgur.com/n5xR79B.gif")?;
+//! if let Some(item) = store.find("magoc")? {
+//!     println!("{}", item.value);
+//! }
+//! # Ok::<(), oxio::Error>(())
+//! ```
+
+mod format;
+mod fs;
+pub mod grep;
+mod levenshtein;
+mod lock;
+pub mod entities;
+pub mod matcher;
+pub mod result;
+pub mod store;
+pub mod stores;
+pub mod sync;
+
+pub use crate::entities::{Item, Metadata, Value};
+pub use crate::result::{Error, Operation, Result};
+pub use crate::store::Store;
+pub use crate::stores::Stores;
@@ -0,0 +1,178 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+
+use crate::result::{Error, IoContext, Result};
+
+/// Name of the file used to coordinate access to a cache directory.
+pub(crate) const LOCK_FILE: &str = ".lock";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockKind {
+    /// Held while reading, and may be held by several processes at once.
+    Shared,
+    /// Held while writing or syncing, excluding all other holders.
+    Exclusive,
+}
+
+/// StoreLock holds an advisory lock on a cache directory, which is released
+/// once dropped.
+pub(crate) struct StoreLock {
+    file: Option<File>,
+    kind: LockKind,
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        if let Some(f) = &self.file {
+            // Exclusive holders clear their PID, so it is not blamed once
+            // released.
+            if self.kind == LockKind::Exclusive {
+                let _ = f.set_len(0);
+            }
+            let _ = FileExt::unlock(f);
+        }
+    }
+}
+
+// The time to wait for a lock can be adjusted through OXIO_LOCK_TIMEOUT, in
+// seconds.
+fn timeout() -> Duration {
+    std::env::var("OXIO_LOCK_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse::<f64>().ok())
+        .filter(|v| *v >= 0.0)
+        .map(Duration::from_secs_f64)
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
+
+fn holder(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+/// Acquires a lock on the provided cache directory, waiting for other
+/// holders up to a timeout. Shared locks on caches that do not exist yet, or
+/// that cannot be written to, are granted without locking, since there is
+/// nothing a writer could be changing under them.
+pub(crate) fn lock(cache: &Path, kind: LockKind) -> Result<StoreLock> {
+    lock_within(cache, kind, timeout())
+}
+
+fn lock_within(cache: &Path, kind: LockKind, timeout: Duration) -> Result<StoreLock> {
+    if kind == LockKind::Shared && !cache.exists() {
+        return Ok(StoreLock { file: None, kind });
+    }
+
+    let path = cache.join(LOCK_FILE);
+    let mut file = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path) {
+        Ok(f) => f,
+        Err(_) if kind == LockKind::Shared => return Ok(StoreLock { file: None, kind }),
+        Err(e) => return Err(e).at(&path),
+    };
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let attempt = match kind {
+            LockKind::Shared => FileExt::try_lock_shared(&file),
+            LockKind::Exclusive => FileExt::try_lock_exclusive(&file),
+        };
+        match attempt {
+            Ok(()) => break,
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {}
+            Err(e) => return Err(e).at(&path),
+        }
+        if Instant::now() >= deadline {
+            let by = match holder(&mut file) {
+                Some(pid) => format!("process {}", pid),
+                None => "another process".to_string(),
+            };
+            return Err(Error::new(&format!("Timed out waiting for {} to release the store at {}",
+                                           by, cache.display())));
+        }
+        sleep(RETRY_INTERVAL);
+    }
+
+    // Record who is holding the lock, so others can report it in case they
+    // time out waiting for it. Shared locks may have several holders, so only
+    // exclusive ones are recorded.
+    if kind == LockKind::Exclusive {
+        let _ = file.set_len(0)
+            .and_then(|_| file.seek(SeekFrom::Start(0)))
+            .and_then(|_| file.write_all(std::process::id().to_string().as_bytes()));
+    }
+
+    Ok(StoreLock { file: Some(file), kind })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+
+    const WAIT: Duration = Duration::from_millis(200);
+
+    // TempDir is a temporary directory, removed once dropped.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            let name: String = thread_rng().sample_iter(&Alphanumeric).take(12).map(char::from).collect();
+            let dir = std::env::temp_dir().join(format!("oxio-test-{}", name));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn shared_locks_block_exclusive_ones() {
+        let dir = TempDir::new();
+        let first = lock_within(&dir.0, LockKind::Shared, WAIT).unwrap();
+        let second = lock_within(&dir.0, LockKind::Shared, WAIT).unwrap();
+        assert!(lock_within(&dir.0, LockKind::Exclusive, WAIT).is_err());
+        drop(first);
+        assert!(lock_within(&dir.0, LockKind::Exclusive, WAIT).is_err());
+        drop(second);
+        assert!(lock_within(&dir.0, LockKind::Exclusive, WAIT).is_ok());
+    }
+
+    #[test]
+    fn timeouts_name_the_exclusive_holder() {
+        let dir = TempDir::new();
+        let held = lock_within(&dir.0, LockKind::Exclusive, WAIT).unwrap();
+        let err = lock_within(&dir.0, LockKind::Shared, WAIT).err().unwrap().to_string();
+        assert!(err.contains(&format!("process {} ", std::process::id())), "{}", err);
+        assert!(lock_within(&dir.0, LockKind::Exclusive, WAIT).is_err());
+
+        // Released locks clear the PID they recorded
+        drop(held);
+        let contents = std::fs::read_to_string(dir.0.join(LOCK_FILE)).unwrap();
+        assert!(contents.is_empty());
+        assert!(lock_within(&dir.0, LockKind::Shared, WAIT).is_ok());
+    }
+
+    #[test]
+    fn missing_caches_only_grant_shared_locks() {
+        let dir = TempDir::new();
+        let cache = dir.0.join("cache");
+        assert!(lock_within(&cache, LockKind::Shared, WAIT).is_ok());
+        assert!(!cache.exists());
+        assert!(lock_within(&cache, LockKind::Exclusive, WAIT).is_err());
+    }
+}
@@ -1,22 +1,24 @@
-use std::iter::Skip;
-use std::env::Args;
-use std::process::exit;
-use std::iter;
+use std::io::{Read, Write};
 
-use clipboard::ClipboardProvider;
-use clipboard::ClipboardContext;
 use atty::Stream;
 use colored::Colorize;
+use regex::RegexBuilder;
 
-use crate::HowNormalize::*;
-use crate::entities::{Item, group_items};
-use crate::sync::CanSync;
+use oxio::{Error, Item, Store, Stores, Value};
+use oxio::entities::group_items;
+use oxio::grep::LineMatch;
+use oxio::matcher::Matcher;
+use oxio::store::is_valid_name;
+use oxio::stores::DEFAULT_STORE;
+use oxio::sync::{CanSync, CommitSummary, Conflict, ItemChange, Resolution, Revision, SyncStatus, SyncStep};
 
-mod fs;
-mod entities;
-mod levenshtein;
-mod sync;
-mod result;
+use crate::cli::{Command, Strategy, StoresCommand, ValueSource};
+use crate::output::{code, item_node, Node};
+
+mod cli;
+mod clip;
+mod output;
+mod pick;
 mod print;
 
 fn help() {
@@ -33,17 +35,52 @@ AUTHOR:
 
 
 USAGE:
-   {ox} {itemna}                    Finds and copies {itemna} to
-                                    clipboard
-   {ox} {grpname} {itemna}          Finds exactly {itemna} in {grpname}
-   {ox} {grpname} {itemna} {val}    Sets {val} to {itemna} in {grpname}
-   {ox} {ed} {grpname} {itemna}     Opens the default editor to edit or
+   {ox} {get} [{grpname}] {itemna}      Finds {itemna}, optionally in
+                                    {grpname}, and copies it to clipboard
+   {ox} {set} {grpname} {itemna} {val}  Sets {val} to {itemna} in {grpname}
+   {ox} {set} {grpname} {itemna} -      Reads the value from stdin. Piping
+                                    a value to {ox} {grpname} {itemna}
+                                    does the same
+   {ox} {set} {grpname} {itemna} --file {path}
+                                    Reads the value from {path}. Pass
+                                    --trim to remove trailing newlines
+   {ox} {ed} {grpname} {itemna}         Opens the default editor to edit or
                                     create {itemna} in {grpname}
-   {ox} {l}                         Lists all items
-   {ox} {rm_grp} {grpname}          Removes a group and all its items
-   {ox} {rm_it} {grpname} {itemna}  Removes {itemna} from {grpname}
+   {ox} {ls} [{grpname}]                Lists all items, or items in
+                                    {grpname}
+   {ox} {sh} [--id] [{grpname}] {itemna}
+                                    Shows details about {itemna}, or
+                                    only its ID when --id is provided
+   {ox} {pk} [{query}]                Picks an item interactively, filtering
+                                    items as {query} is typed
+   {ox} {se} [--threshold {n}] {query}
+                                    Lists items matching {query} by
+                                    group or name, best matches first.
+                                    Matches score from 0 to 1, and the
+                                    ones below {n} are left out
+   {ox} {gr} [-i] [--names] {pat}
+                                    Lists lines of values matching the
+                                    regular expression {pat}, ignoring
+                                    case with -i. Pass --names to also
+                                    match {grpname}/{itemna}
+   {ox} {hist} {grpname} {itemna}      Lists past values of {itemna}, when
+                                    the store is synced
+   {ox} {rst} {grpname} {itemna} [{rev}]
+                                    Brings back the value {itemna} had
+                                    at {rev}, or its previous value,
+                                    even if it was removed
+   {ox} {rm} {grpname} {itemna}         Removes {itemna} from {grpname}
+   {ox} {rm} --group {grpname}          Removes a group and all its items
    {ox} {sn}                        Syncs all items and rebuilds the
                                     index. See README on how to use this
+   {ox} {sn} --keep-local|--keep-remote|--keep-both|--edit
+                                    Syncs, resolving items changed on
+                                    both sides as requested instead of
+                                    asking about each one
+   {ox} {sn} --dry-run                Shows what {ox} {sn} would do,
+                                    without changing anything
+   {ox} {sn} status                   Lists local changes and commits yet
+                                    to be pushed or merged
    {ox} {sn} {ni} {u}               Initializes the local cache with contents
                                     from the provided Git URL. After that, use
                                     {ox} {sn} to update the remote repository
@@ -54,24 +91,66 @@ USAGE:
                                     and local cache.
    {ox} {rindx}                     Forces all items in the local cache
                                     to be reindexed
+   {ox} {mig}                     Upgrades items in the local cache
+                                    written by older versions of oxio
    {ox} {hp}                        Shows this message
 
+   {ox} {st}                      Lists all known stores
+   {ox} {st} {add} {sname} [{path}]   Registers a new store, optionally
+                                    kept at {path}
+   {ox} {st} {rmv} {sname}          Unregisters a store, keeping its items
+   {ox} {st} {def} {sname}         Uses {sname} when no store is provided
+
+SHORTHANDS:
+   {ox} {itemna}                    Same as {ox} {get} {itemna}
+   {ox} {grpname} {itemna}          Same as {ox} {get} {grpname} {itemna}
+   {ox} {grpname} {itemna} {val}    Same as {ox} {set} {grpname} {itemna} {val}
+   {ox} {l}                         Same as {ox} {ls}
+   {ox} {rm_it} {grpname} {itemna}  Same as {ox} {rm} {grpname} {itemna}
+   {ox} {rm_grp} {grpname}          Same as {ox} {rm} --group {grpname}
+
+OPTIONS:
+   --store {sname}|{path}             Uses a named store, or the cache located
+                                    at {path}, instead of the default store.
+                                    The default location can also be set
+                                    through the OXIO_HOME environment
+                                    variable.
+   --all-stores                     Looks up items across all stores
+   --format text|json|yaml|tsv      Writes results, including full values
+                                    and metadata, in a format suitable
+                                    for scripts. Errors are written to
+                                    stderr as JSON
+   -h, --help                       Shows this message
+   -V, --version                    Shows the version of oxio
+   --                               Takes all following arguments literally,
+                                    even when they start with a dash
+
 VERSION:
-   0.1.3
+   {version}
 ", ox = "oxio".cyan(), itemna = "ITEMNAME".blue(), grpname = "GROUPNAME".blue(),
                            val = "VALUE".blue(), u = "URL".blue(),
+                           get = "get".yellow(), set = "set".yellow(), ls = "ls".yellow(), rm = "rm".yellow(),
                            l = "all".yellow(), rm_grp = "rm-group".yellow(), rm_it = "rm-item".yellow(),
                            sn = "sync".yellow(), ni = "init".yellow(), mrg = "merge".yellow(),
-                           rindx = "reindex".yellow(), hp = "help".yellow(), ed = "edit".yellow());
+                           rindx = "reindex".yellow(), mig = "migrate".yellow(), sh = "show".yellow(),
+                           hp = "help".yellow(), ed = "edit".yellow(),
+                           pk = "pick".yellow(), query = "QUERY".blue(),
+                           se = "search".yellow(), n = "N".blue(),
+                           gr = "grep".yellow(), pat = "PATTERN".blue(),
+                           hist = "history".yellow(), rst = "restore".yellow(), rev = "REV".blue(),
+                           st = "stores".yellow(), add = "add".yellow(), rmv = "remove".yellow(),
+                           def = "default".yellow(), sname = "STORE".blue(), path = "PATH".blue(),
+                           version = env!("CARGO_PKG_VERSION"));
     eprintln!("{}", help_str);
 }
 
-enum HowNormalize<'a> {
-    AsIs(&'a mut Skip<Args>),
-    Lowercase(&'a mut Skip<Args>),
+struct Context {
+    stores: Stores,
+    store: Store,
+    all_stores: bool,
 }
 
-fn truncate_output(s: &mut String) -> String {
+fn truncate_output(s: &str) -> String {
     if s.contains('\n') {
         if s.len() <= 60 {
             s.to_string()
@@ -85,6 +164,20 @@ fn truncate_output(s: &mut String) -> String {
     }
 }
 
+// Returns a short representation of an item's value, suitable for listings.
+// Only the item's summary is used, so values need not be loaded.
+fn preview(i: &Item) -> String {
+    if !i.meta.content_type.starts_with("text/") {
+        return format!("<{}, {} bytes>", i.meta.content_type, i.summary.length);
+    }
+    let preview = truncate_output(&i.summary.preview);
+    if i.summary.is_truncated() && !preview.ends_with("...") {
+        preview + "..."
+    } else {
+        preview
+    }
+}
+
 fn trim_newline(s: &mut String) {
     if s.ends_with('\n') {
         s.pop();
@@ -94,13 +187,6 @@ fn trim_newline(s: &mut String) {
     }
 }
 
-fn normalize_argument(how: HowNormalize) -> String {
-    match how {
-        AsIs(s) => s.next().unwrap(),
-        Lowercase(s) => s.next().unwrap().to_lowercase()
-    }
-}
-
 fn largest_item_name(items: &[Item]) -> usize {
     if let Some(i) = items.iter().max_by(|a, b| a.name.len().cmp(&b.name.len())) {
         i.name.len()
@@ -109,327 +195,1028 @@ fn largest_item_name(items: &[Item]) -> usize {
     }
 }
 
-fn copy_or_echo(mut i: Item) {
-    let res = i.fill_value();
-    if res.is_err() {
-        ox_eprintln!("Error loading item {}: {}", i.filename, res.err().unwrap());
-        ox_eprintln!("Try running {}.", "oxio reindex".yellow());
-        exit(1);
+// Progress is only reported to people; structured formats emit the outcome.
+fn print_progress(step: SyncStep) {
+    if !output::is_structured() {
+        ox_println!("{}", step);
     }
+}
 
-    if atty::is(Stream::Stdout) {
-        let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-        let res = ctx.set_contents(i.value.clone());
-        if res.is_err() {
-            ox_eprintln!("Error writing to clipboard: {}", res.err().unwrap());
-            exit(1);
-        }
-        ox_println!("{} (from {}->{}) is now in your clipboard!", i.value.magenta(), i.group.blue(), i.name.blue());
-    } else {
-        println!("{}", i.value);
+// Describes where an item came from, including its store when looking up
+// items across all stores.
+fn origin(ctx: &Context, store: &Store, i: &Item) -> String {
+    match store.name() {
+        Some(name) if ctx.all_stores => format!("{}:{}->{}", name.cyan(), i.group.blue(), i.name.blue()),
+        _ => format!("{}->{}", i.group.blue(), i.name.blue()),
     }
 }
 
-// help, all, sync, reindex, <itm>
+// Same as origin, without colors.
+fn plain_origin(ctx: &Context, store: &Store, i: &Item) -> String {
+    match store.name() {
+        Some(name) if ctx.all_stores => format!("{}:{}->{}", name, i.group, i.name),
+        _ => format!("{}->{}", i.group, i.name),
+    }
+}
 
-fn handle_one_word(args: &mut Skip<Args>) {
-    match normalize_argument(Lowercase(args)).as_str() {
-        "help" => help(),
-        "all" => {
-            match fs::get_all_items() {
-                Err(err) => {
-                    ox_eprintln!("Error reading items: {}", err);
-                    exit(1)
-                }
-                Ok(items) => {
-                    if items.is_empty() {
-                        ox_eprintln!("Your store is empty. Use {} to create a new item", "oxio GROUP ITEM VALUE".yellow());
-                        return;
-                    }
-                    let groups = group_items(items);
-                    for (n, items) in groups {
-                        println!("{}:", n.yellow());
-                        let max_name = largest_item_name(&items);
-                        for mut i in items {
-                            if let Err(e) = i.fill_value() {
-                                ox_eprintln!("Error loading item {}: {}", i.filename, e);
-                                exit(1)
-                            }
-                            let pad = iter::repeat(" ").take(max_name - i.name.len()).collect::<String>();
-                            println!("  {}{}: {}", pad, i.name.blue(), truncate_output(&mut i.value).magenta())
-                        }
-                        println!();
-                    }
-                }
-            }
+fn copy_or_echo(ctx: &Context, store: &Store, i: Item) {
+    if output::is_structured() {
+        output::emit(&item_node(store, &i));
+    } else if atty::is(Stream::Stdout) {
+        if let Err(e) = clip::copy(&i.value, &i.meta.content_type) {
+            ox_fail!(error: e, "Error writing to clipboard: {}", e);
         }
-        "sync" => {
-            let status = match sync::can_sync_cache() {
-                Err(e) => {
-                    ox_eprintln!("Error determining repository status: {}", e);
-                    exit(1)
-                }
-                Ok(status) => status
-            };
-
-            match status {
-                CanSync::Yes => {
-                    let repo = match sync::get_local_repository() {
-                        Err(e) => {
-                            ox_eprintln!("Error reading local repository: {}", e);
-                            exit(1)
-                        }
-                        Ok(r) => r
-                    };
-                    let sync_result = match sync::perform_sync(&repo) {
-                        Err(e) => {
-                            ox_eprintln!("Error performing sync: {}", e);
-                            exit(1);
-                        }
-                        Ok(()) => fs::index()
-                    };
-                    match sync_result {
-                        Err(e) => {
-                            ox_eprintln!("Error indexing local cache: {}", e);
-                            exit(1);
-                        }
-                        Ok(items) => ox_println!("Sync completed. {} item(s) on local cache.", items)
-                    }
-                }
-                CanSync::NotConfigured(reason) => {
-                    ox_eprintln!("Cannot perform sync: {}", reason);
-                    exit(1);
-                }
-                CanSync::NoRemotes => {
-                    ox_eprintln!("Cannot perform sync: The cache already contains a repository, but it does not contain a remote.");
-                    exit(1);
-                }
-                CanSync::NoLocalCache => {
-                    ox_eprintln!("Cannot perform sync: You don't have a local cache. Either initialize a new by adding new items, or use {} to download a repository", "oxio sync init URL".yellow());
-                    ox_eprintln!("For further information, please refer to the README.");
-                    exit(1);
+        ox_println!("{} (from {}) is now in your clipboard!", preview(&i).magenta(), origin(ctx, store, &i));
+    } else {
+        match &i.value {
+            // Values ending with a newline are written exactly as stored
+            Value::Text(s) if s.ends_with('\n') => print!("{}", s),
+            Value::Text(s) => println!("{}", s),
+            Value::Binary(b) => {
+                if let Err(e) = std::io::stdout().write_all(b) {
+                    ox_fail!("Error writing value: {}", e);
                 }
             }
         }
-        "reindex" => {
-            match fs::index() {
-                Err(e) => {
-                    ox_eprintln!("Error reindexing: {}", e);
-                    exit(1);
-                }
-                Ok(len) => ox_println!("Reindex completed. {} item(s)", len)
+    }
+}
+
+fn list_items(store: &Store, group: Option<&str>) -> Vec<Item> {
+    let items = match group {
+        Some(g) => store.list_group(g),
+        None => store.list(),
+    };
+    match items {
+        Err(err) => ox_fail!(error: err, "Error reading items: {}", err),
+        Ok(items) => {
+            if let (true, Some(g)) = (items.is_empty(), group) {
+                ox_fail!(code: code::NOT_FOUND, "No group named {} found.", g.yellow());
             }
+            items
         }
-        name => {
-            match fs::find_item(name) {
-                Err(err) => {
-                    ox_eprintln!("Error searching items: {}", err);
-                    exit(1)
-                }
-                Ok(opt) => {
-                    if let Some(item) = opt {
-                        copy_or_echo(item)
-                    } else {
-                        ox_eprintln!("No item named {} was found", name.blue());
-                        exit(1)
-                    }
-                }
+    }
+}
+
+fn print_all(items: Vec<Item>) {
+    if items.is_empty() {
+        ox_eprintln!("Your store is empty. Use {} to create a new item", "oxio set GROUP ITEM VALUE".yellow());
+        return;
+    }
+    let groups = group_items(items);
+    for (n, items) in groups {
+        println!("{}:", n.yellow());
+        let max_name = largest_item_name(&items);
+        for i in items {
+            let pad = " ".repeat(max_name - i.name.len());
+            println!("  {}{}: {}", pad, i.name.blue(), preview(&i).magenta());
+        }
+        println!();
+    }
+}
+
+// Describes items along with their full values, which are loaded as needed.
+fn item_nodes(store: &Store, items: Vec<Item>) -> Vec<Node> {
+    items.into_iter()
+        .map(|mut i| {
+            if let Err(e) = store.load(&mut i) {
+                ox_fail!(error: e, "Error reading {} (in {}): {}", i.name, i.group, e);
             }
+            item_node(store, &i)
+        })
+        .collect()
+}
+
+// Returns the stores an invocation operates on.
+fn selected_stores(ctx: &Context) -> Vec<Store> {
+    if ctx.all_stores {
+        match ctx.stores.open_all() {
+            Err(err) => ox_fail!(error: err, "Error opening stores: {}", err),
+            Ok(all) => all
         }
+    } else {
+        vec![ctx.store.clone()]
     }
 }
 
-// rm-group, <grp> <itm>
+fn handle_list(ctx: &Context, group: Option<&str>) {
+    let stores = selected_stores(ctx);
 
-fn handle_two_words(args: &mut Skip<Args>) {
-    match normalize_argument(Lowercase(args)).as_str() {
-        "rm-group" => {
-            let group = normalize_argument(Lowercase(args));
-            match fs::get_all_group(&group) {
-                Err(e) => {
-                    ox_eprintln!("Error loading items: {}", e);
-                    exit(1)
-                }
-                Ok(items) => {
-                    if items.is_empty() {
-                        ox_eprintln!("No group named {} found.", group.yellow());
-                        exit(1)
-                    }
-                    for mut i in items {
-                        if let Err(e) = i.delete() {
-                            ox_eprintln!("Error removing {}: {}", i.filename, e);
-                            exit(1)
-                        }
-                    }
-                    match fs::index() {
-                        Err(e) => {
-                            ox_eprintln!("Error reindexing: {}", e);
-                            exit(1)
-                        }
-                        Ok(_) => ox_println!("Removed group {} and all its items.", group.yellow())
-                    }
-                }
-            }
+    if output::is_structured() {
+        let nodes = stores.iter()
+            .flat_map(|store| item_nodes(store, list_items(store, group)))
+            .collect();
+        stores.iter().for_each(report_unreadable);
+        return output::emit(&Node::List(nodes));
+    }
+    for store in &stores {
+        if ctx.all_stores {
+            println!("{} {}", "store".cyan(), store.name().unwrap_or(DEFAULT_STORE).cyan());
         }
-        group_name => {
-            let item_name = normalize_argument(Lowercase(args));
-            match fs::get_item(&group_name.to_string(), &item_name) {
-                Err(e) => {
-                    ox_eprintln!("Error obtaining item: {}", e);
-                    exit(1)
-                }
-                Ok(item) => {
-                    if let Some(i) = item {
-                        copy_or_echo(i);
-                    } else {
-                        ox_eprintln!("Could not find an item named {} on group {}", item_name.blue(), group_name.yellow());
-                        exit(1)
-                    }
-                }
-            }
+        print_all(list_items(store, group));
+        report_unreadable(store);
+    }
+}
+
+// Warns about files left out of listings since they cannot be read. The
+// listing itself already succeeded, so failing to tell them is not an error.
+fn report_unreadable(store: &Store) {
+    for c in store.unreadable().unwrap_or_default() {
+        ox_eprintln!("Skipped {}: {}", c.filename, c.error);
+    }
+}
+
+// Looks up an item by exact group and name, or by approximate name when no
+// group is provided.
+fn lookup(ctx: &Context, group: Option<&str>, name: &str) -> Option<(Store, Item)> {
+    let store = &ctx.store;
+    let found = match group {
+        None => return find(ctx, name),
+        Some(group) if ctx.all_stores => ctx.stores.get_item(group, name),
+        Some(group) => store.get(group, name).map(|o| o.map(|i| (store.clone(), i))),
+    };
+    match found {
+        Err(err) => ox_fail!(error: err, "Error searching items: {}", err),
+        Ok(found) => found,
+    }
+}
+
+// Finds an item by approximate name. When several items match equally well,
+// people on a terminal pick one of them, while scripts get the candidates
+// listed along with a distinct exit code.
+fn find(ctx: &Context, name: &str) -> Option<(Store, Item)> {
+    let matches = if ctx.all_stores {
+        ctx.stores.matches(name)
+    } else {
+        ctx.store.matches(name).map(|m| m.into_iter().map(|i| (ctx.store.clone(), i)).collect())
+    };
+    let mut matches = match matches {
+        Err(err) => ox_fail!(error: err, "Error searching items: {}", err),
+        Ok(m) => m,
+    };
+    let (store, mut item) = match matches.len() {
+        0 => return None,
+        1 => matches.remove(0),
+        _ if interactive() => pick_item(ctx, matches, ""),
+        _ => {
+            let candidates = matches.iter().map(|(s, i)| plain_origin(ctx, s, i)).collect();
+            let e = Error::ambiguous(name, candidates);
+            ox_fail!(error: e, e)
         }
+    };
+    if let Err(e) = store.load(&mut item) {
+        ox_fail!(error: e, "Error reading {} (in {}): {}", item.name, item.group, e);
     }
+    Some((store, item))
 }
 
-// rm-item, edit, sync <>, add new item
+// Whether a person is around to answer prompts.
+fn interactive() -> bool {
+    atty::is(Stream::Stdin) && atty::is(Stream::Stderr)
+}
 
-fn handle_three_words(args: &mut Skip<Args>) {
-    match normalize_argument(Lowercase(args)).as_str() {
-        "rm-item" => {
-            let group = normalize_argument(Lowercase(args));
-            let name = normalize_argument(Lowercase(args));
-            match fs::get_item(&group, &name) {
-                Err(err) => {
-                    ox_eprintln!("Error locating {}: {}", name.blue(), err);
-                    exit(1);
-                }
-                Ok(item) => {
-                    if let Some(mut item) = item {
-                        if let Err(err) = item.delete() {
-                            ox_eprintln!("Error removing {} from {}: {}", name.blue(), group.yellow(), err);
-                            exit(1);
-                        }
-                        ox_println!("Removed {} from {}", name.blue(), group.yellow());
-                    } else {
-                        ox_eprintln!("Could not find {} in {}", name.blue(), group.yellow());
-                        exit(1)
-                    }
-                }
-            }
-            if let Err(e) = fs::index() {
-                ox_eprintln!("Error reindexing local cache: {}", e);
-                exit(1)
-            }
+// Lets the user choose one of the provided items. Values are not loaded.
+fn pick_item(ctx: &Context, mut items: Vec<(Store, Item)>, query: &str) -> (Store, Item) {
+    let entries = items.iter()
+        .map(|(store, i)| pick::Entry {
+            label: plain_origin(ctx, store, i),
+            preview: preview(i),
+        })
+        .collect::<Vec<_>>();
+    match pick::pick(&entries, query) {
+        Err(e) => {
+            let e = Error::from(e);
+            ox_fail!(error: e, "Could not start the picker: {}", e)
         }
-        "sync" => handle_sync_command(normalize_argument(Lowercase(args)).as_str(), args),
-        "edit" => handle_edit_command(args),
-        group => {
-            if !fs::is_valid_name(&group.to_string()) {
-                ox_eprintln!("Invalid group name {}", group.yellow());
-                exit(1)
-            }
+        Ok(None) => ox_fail!("Nothing was picked"),
+        Ok(Some(n)) => items.swap_remove(n),
+    }
+}
 
-            let name = normalize_argument(Lowercase(args));
-            if !fs::is_valid_name(&name) {
-                ox_eprintln!("Invalid item name {}", name.blue());
-                exit(1)
-            }
-            let mut value = normalize_argument(AsIs(args));
-            match fs::create_item(&group.to_string(), &name, &mut value) {
-                Err(e) => {
-                    ox_eprintln!("Error creating item: {}", e);
-                    exit(1);
+fn handle_search(ctx: &Context, query: &str, threshold: Option<f64>) {
+    let mut matcher = Matcher::new().groups(true);
+    if let Some(t) = threshold {
+        matcher = matcher.threshold(t);
+    }
+    let results = if ctx.all_stores {
+        ctx.stores.search(&matcher, query)
+    } else {
+        ctx.store.search(&matcher, query)
+            .map(|r| r.into_iter().map(|(score, i)| (score, (ctx.store.clone(), i))).collect())
+    };
+    let results = match results {
+        Err(err) => ox_fail!(error: err, "Error searching items: {}", err),
+        Ok(r) => r,
+    };
+    if results.is_empty() {
+        ox_fail!(code: code::NOT_FOUND, "Nothing matches {}", query.blue());
+    }
+
+    if output::is_structured() {
+        let nodes = results.into_iter()
+            .map(|(score, (store, mut i))| {
+                if let Err(e) = store.load(&mut i) {
+                    ox_fail!(error: e, "Error reading {} (in {}): {}", i.name, i.group, e);
                 }
-                Ok(()) => {
-                    ox_println!("Ok, {} (in {}) is {}", name.blue(), group.yellow(), value.magenta());
+                let mut node = item_node(&store, &i);
+                if let Node::Object(fields) = &mut node {
+                    fields.insert(0, ("score", score.into()));
                 }
-            }
+                node
+            })
+            .collect();
+        return output::emit(&Node::List(nodes));
+    }
+    for (score, (store, i)) in results {
+        println!("{}  {}: {}", format!("{:.2}", score).dimmed(), origin(ctx, &store, &i), preview(&i).magenta());
+    }
+}
+
+// Marks the parts of a line matching a pattern. Long lines are shortened
+// around the first match.
+fn highlight(line: &LineMatch) -> String {
+    const BEFORE: usize = 40;
+    const WIDTH: usize = 160;
+    let text = &line.text;
+    let first = line.ranges.first().map(|r| r.0).unwrap_or(0);
+    let mut start = if text.len() > WIDTH { first.saturating_sub(BEFORE) } else { 0 };
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = text.len().min(start + WIDTH);
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+
+    let mut out = if start > 0 { "...".to_string() } else { String::new() };
+    let mut pos = start;
+    for &(s, e) in &line.ranges {
+        let (s, e) = (s.max(pos), e.min(end));
+        if s >= e {
+            continue;
         }
+        out.push_str(&text[pos..s]);
+        out.push_str(&text[s..e].red().bold().to_string());
+        pos = e;
+    }
+    out.push_str(&text[pos..end]);
+    if end < text.len() {
+        out.push_str("...");
     }
+    out
 }
 
-// sync init, sync merge
+fn handle_grep(ctx: &Context, pattern: &str, ignore_case: bool, names: bool) {
+    let regex = match RegexBuilder::new(pattern).case_insensitive(ignore_case).build() {
+        Err(e) => output::fail(code::USAGE, &format!("Invalid pattern: {}", e), None),
+        Ok(r) => r,
+    };
+    let mut results = vec![];
+    for store in selected_stores(ctx) {
+        match store.grep(&regex, names) {
+            Err(err) => ox_fail!(error: err, "Error searching items: {}", err),
+            Ok(found) => results.extend(found.into_iter().map(|m| (store.clone(), m))),
+        }
+    }
+    if results.is_empty() {
+        ox_fail!(code: code::NOT_FOUND, "Nothing matches {}", pattern.blue());
+    }
 
-fn handle_sync_command(cmd: &str, args: &mut Skip<Args>) {
-    let url = normalize_argument(AsIs(args));
-    match cmd {
-        "init" => {
-            if let Err(e) = sync::init_sync_empty(url) {
-                ox_eprintln!("Error executing: {}", e);
-                exit(1)
+    if output::is_structured() {
+        let nodes = results.into_iter()
+            .map(|(store, m)| {
+                let lines = m.lines.into_iter()
+                    .map(|l| Node::Object(vec![
+                        ("line", l.number.into()),
+                        ("text", l.text.into()),
+                        ("ranges", Node::List(l.ranges.into_iter()
+                            .map(|(s, e)| Node::List(vec![s.into(), e.into()]))
+                            .collect())),
+                    ]))
+                    .collect();
+                Node::Object(vec![
+                    ("store", store.name().into()),
+                    ("group", m.item.group.as_str().into()),
+                    ("name", m.item.name.as_str().into()),
+                    ("id", m.item.id().into()),
+                    ("name_matched", m.path_matched.into()),
+                    ("lines", Node::List(lines)),
+                ])
+            })
+            .collect();
+        return output::emit(&Node::List(nodes));
+    }
+    for (store, m) in results {
+        println!("{}", origin(ctx, &store, &m.item));
+        for line in &m.lines {
+            println!("  {}: {}", line.number.to_string().dimmed(), highlight(line));
+        }
+    }
+}
+
+fn handle_pick(ctx: &Context, query: &str) {
+    let stores = selected_stores(ctx);
+    let items = stores.iter()
+        .flat_map(|store| list_items(store, None).into_iter().map(move |i| (store.clone(), i)))
+        .collect::<Vec<_>>();
+    if items.is_empty() {
+        ox_fail!(code: code::NOT_FOUND, "Your store is empty. Use {} to create a new item", "oxio set GROUP ITEM VALUE".yellow());
+    }
+    let (store, mut item) = pick_item(ctx, items, query);
+    if let Err(e) = store.load(&mut item) {
+        ox_fail!(error: e, "Error reading {} (in {}): {}", item.name, item.group, e);
+    }
+    copy_or_echo(ctx, &store, item);
+}
+
+fn handle_get(ctx: &Context, group: Option<&str>, name: &str) {
+    match (lookup(ctx, group, name), group) {
+        (Some((from, item)), _) => copy_or_echo(ctx, &from, item),
+        (None, None) => ox_fail!(code: code::NOT_FOUND, "No item named {} was found", name.blue()),
+        (None, Some(group)) => {
+            ox_fail!(code: code::NOT_FOUND, "Could not find an item named {} on group {}", name.blue(), group.yellow())
+        }
+    }
+}
+
+// Whether stdin is a pipe or a redirected file, as opposed to a terminal or
+// a device such as /dev/null, which scripts and services often inherit.
+#[cfg(unix)]
+fn stdin_piped() -> bool {
+    use std::os::fd::AsFd;
+    use std::os::unix::fs::FileTypeExt;
+    std::io::stdin().as_fd().try_clone_to_owned()
+        .map(std::fs::File::from)
+        .and_then(|f| f.metadata())
+        .map(|m| m.file_type().is_fifo() || m.file_type().is_file())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn stdin_piped() -> bool {
+    !atty::is(Stream::Stdin)
+}
+
+// Reads the value of an item being set. Values are kept exactly as provided,
+// including trailing newlines, unless trimming was requested. Empty stdin is
+// refused, as it more likely means nothing was piped than an empty value.
+fn read_value(source: ValueSource, trim: bool) -> Value {
+    let bytes = match source {
+        ValueSource::Argument(s) => s.into_bytes(),
+        ValueSource::Stdin => {
+            let mut buf = vec![];
+            if let Err(e) = std::io::stdin().read_to_end(&mut buf) {
+                ox_fail!("Error reading value from stdin: {}", e);
             }
+            if buf.is_empty() {
+                ox_fail!(code: code::USAGE, "No value was read from stdin. Pass an empty string as the value to store one.");
+            }
+            buf
         }
-        "merge" => {
-            if let Err(e) = sync::init_sync_existing(url) {
-                ox_eprintln!("Error executing: {}", e);
-                exit(1)
+        ValueSource::File(path) => match std::fs::read(&path) {
+            Err(e) => {
+                let e = Error::Io { source: e, path: Some(path) };
+                ox_fail!(error: e, "Error reading {}", e)
             }
+            Ok(b) => b
+        },
+    };
+    let mut value = Value::from_bytes(bytes);
+    if let (true, Value::Text(s)) = (trim, &mut value) {
+        let len = s.trim_end_matches(&['\r', '\n'][..]).len();
+        s.truncate(len);
+    }
+    value
+}
+
+// Reports an item that was just written.
+fn print_written(store: &Store, group: &str, name: &str, description: &str) {
+    if !output::is_structured() {
+        return ox_println!("Ok, {} (in {}) is {}", name.blue(), group.yellow(), description.magenta());
+    }
+    match store.get(group, name) {
+        Err(e) => ox_fail!(error: e, "Error reading item: {}", e),
+        Ok(None) => ox_fail!(code: code::NOT_FOUND, "Could not find {} in {}", name, group),
+        Ok(Some(item)) => output::emit(&item_node(store, &item)),
+    }
+}
+
+fn handle_set(store: &Store, group: &str, name: &str, value: Value) {
+    if !is_valid_name(group) {
+        ox_fail!(error: Error::invalid_name("group", group), "Invalid group name {}", group.yellow());
+    }
+    if !is_valid_name(name) {
+        ox_fail!(error: Error::invalid_name("item", name), "Invalid item name {}", name.blue());
+    }
+    let description = match &value {
+        Value::Text(s) => truncate_output(s),
+        Value::Binary(b) => format!("<{}, {} bytes>", value.guess_content_type(), b.len()),
+    };
+    match store.set(group, name, value) {
+        Err(e) => ox_fail!(error: e, "Error creating item: {}", e),
+        Ok(()) => print_written(store, group, name, &description),
+    }
+    auto_commit(store);
+}
+
+// Commits the changes just written when OXIO_AUTO_COMMIT is set. Failing to
+// do so does not undo the write, since the next sync commits anything left
+// behind, but is reported so nobody assumes it was committed.
+fn auto_commit(store: &Store) {
+    if !oxio::sync::auto_commit_enabled() {
+        return;
+    }
+    if let Err(e) = store.commit_changes() {
+        ox_eprintln!("Warning: changes were saved, but not committed: {}. {} commits them.", e, "oxio sync".yellow());
+    }
+}
+
+fn removal_node(group: &str, name: Option<&str>, removed: usize) -> Node {
+    Node::Object(vec![
+        ("group", group.into()),
+        ("name", name.into()),
+        ("removed", removed.into()),
+    ])
+}
+
+fn handle_remove(store: &Store, group: &str, name: &str) {
+    match store.delete(group, name) {
+        Err(err) => ox_fail!(error: err, "Error removing {} from {}: {}", name.blue(), group.yellow(), err),
+        Ok(true) if output::is_structured() => output::emit(&removal_node(group, Some(name), 1)),
+        Ok(true) => ox_println!("Removed {} from {}", name.blue(), group.yellow()),
+        Ok(false) => ox_fail!(code: code::NOT_FOUND, "Could not find {} in {}", name.blue(), group.yellow()),
+    }
+    auto_commit(store);
+}
+
+fn handle_remove_group(store: &Store, group: &str) {
+    match store.delete_group(group) {
+        Err(e) => ox_fail!(error: e, "Error removing group {}: {}", group.yellow(), e),
+        Ok(0) => ox_fail!(code: code::NOT_FOUND, "No group named {} found.", group.yellow()),
+        Ok(n) if output::is_structured() => output::emit(&removal_node(group, None, n)),
+        Ok(_) => ox_println!("Removed group {} and all its items.", group.yellow())
+    }
+    auto_commit(store);
+}
+
+// Exits with an explanation unless the store can be synced.
+fn ensure_can_sync(store: &Store) {
+    let status = match store.can_sync() {
+        Err(e) => ox_fail!(error: e, "Error determining repository status: {}", e),
+        Ok(status) => status
+    };
+
+    match status {
+        CanSync::Yes => {}
+        CanSync::NotConfigured(reason) => ox_fail!("Cannot perform sync: {}", reason),
+        CanSync::NoRemotes => {
+            ox_fail!("Cannot perform sync: The cache already contains a repository, but it does not contain a remote.")
         }
-        _ => {
-            ox_eprintln!("Unknown command 'sync {}'. Use {} for available options.", cmd, "oxio help".yellow());
-            exit(1)
+        CanSync::NoLocalCache => {
+            output::fail(code::ERROR, &format!("Cannot perform sync: You don't have a local cache. Either initialize a new by adding new items, or use {} to download a repository", "oxio sync init URL".yellow()),
+                         Some("For further information, please refer to the README."))
         }
     }
 }
 
-fn handle_edit_command(args: &mut Skip<Args>) {
-    let group = normalize_argument(Lowercase(args));
-    let item = normalize_argument(Lowercase(args));
-    if !fs::is_valid_name(&group) {
-        ox_eprintln!("Invalid group name {}", group.yellow());
-        exit(1)
+fn handle_sync(store: &Store, strategy: Option<Strategy>, dry_run: bool) {
+    ensure_can_sync(store);
+    if dry_run {
+        return handle_sync_dry_run(store, strategy);
     }
 
-    if !fs::is_valid_name(&item) {
-        ox_eprintln!("Invalid item name {}", item.blue());
-        exit(1)
+    let mut resolved = vec![];
+    let progress = |step: SyncStep| {
+        if let SyncStep::Resolved(c, r) = &step {
+            resolved.push(resolution_node(c, r));
+        }
+        print_progress(step);
+    };
+    match store.sync_with(|c| resolve_conflict(strategy, c), progress) {
+        Err(e) => ox_fail!(error: e, "Error performing sync: {}", e),
+        Ok(items) if output::is_structured() => output::emit(&Node::Object(vec![
+            ("items", items.into()),
+            ("conflicts", Node::List(resolved)),
+        ])),
+        Ok(items) => ox_println!("Sync completed. {} item(s) on local cache.", items)
     }
+}
 
-    let mut value = String::new();
-    let entry = match fs::get_item(&group, &item) {
-        Err(e) => {
-            ox_eprintln!("Error searching index: {}", e);
-            exit(1)
+fn sync_status(store: &Store) -> SyncStatus {
+    ensure_can_sync(store);
+    match store.sync_status() {
+        Err(e) => ox_fail!(error: e, "Error determining sync status: {}", e),
+        Ok(status) => status,
+    }
+}
+
+fn change_line(c: &ItemChange) -> String {
+    format!("{} {}->{}", c.change, c.group.blue(), c.name.blue())
+}
+
+fn commit_line(c: &CommitSummary) -> String {
+    let date = format_timestamp(c.time.max(0) as u64);
+    format!("{} {} {}", c.id.yellow(), c.summary, format!("({}, {})", c.author, date).dimmed())
+}
+
+fn handle_sync_status(store: &Store) {
+    let status = sync_status(store);
+    if output::is_structured() {
+        return output::emit(&sync_status_node(&status));
+    }
+    if status.is_up_to_date() {
+        return ox_println!("Everything is up to date.");
+    }
+
+    if !status.local.is_empty() {
+        ox_println!("{} local change(s), committed on the next sync:", status.local.len());
+        status.local.iter().for_each(|c| println!("  {}", change_line(c)));
+    }
+    if !status.outgoing.is_empty() {
+        ox_println!("{} commit(s) to push:", status.outgoing.len());
+        status.outgoing.iter().for_each(|c| println!("  {}", commit_line(c)));
+    }
+    if !status.incoming.is_empty() {
+        ox_println!("{} commit(s) to merge from the remote:", status.incoming.len());
+        status.incoming.iter().for_each(|c| println!("  {}", commit_line(c)));
+        ox_println!("Incoming changes:");
+        status.incoming_items.iter().for_each(|c| println!("  {}", change_line(c)));
+    }
+    if !status.conflicts.is_empty() {
+        ox_println!("Changed on both sides, to be resolved on the next sync:");
+        status.conflicts.iter().for_each(|c| println!("  {}->{}", c.group.blue(), c.name.blue()));
+    }
+}
+
+fn handle_sync_dry_run(store: &Store, strategy: Option<Strategy>) {
+    let status = sync_status(store);
+    if output::is_structured() {
+        return output::emit(&sync_status_node(&status));
+    }
+    if status.is_up_to_date() {
+        return ox_println!("Nothing to sync.");
+    }
+
+    let list = |changes: &[ItemChange]| changes.iter().map(change_line).collect::<Vec<_>>().join(", ");
+    ox_println!("Sync would:");
+    if !status.local.is_empty() {
+        println!("  commit {} local change(s): {}", status.local.len(), list(&status.local));
+    }
+    if !status.incoming.is_empty() {
+        println!("  merge {} incoming commit(s): {}", status.incoming.len(), list(&status.incoming_items));
+    }
+    if !status.conflicts.is_empty() {
+        let how = match strategy {
+            Some(Strategy::KeepLocal) => "keeping the local versions",
+            Some(Strategy::KeepRemote) => "keeping the remote versions",
+            Some(Strategy::KeepBoth) => "keeping both versions",
+            Some(Strategy::Edit) => "editing both versions together",
+            None if interactive() => "asking about each of them",
+            None => "keeping both versions",
+        };
+        let items = status.conflicts.iter()
+            .map(|c| format!("{}->{}", c.group.blue(), c.name.blue()))
+            .collect::<Vec<_>>();
+        println!("  resolve {} item(s) changed on both sides, {}: {}", items.len(), how, items.join(", "));
+    }
+    if !status.local.is_empty() || !status.outgoing.is_empty() {
+        println!("  push local commits to the remote");
+    }
+}
+
+fn sync_status_node(status: &SyncStatus) -> Node {
+    let changes = |changes: &[ItemChange]| Node::List(changes.iter()
+        .map(|c| Node::Object(vec![
+            ("group", c.group.as_str().into()),
+            ("name", c.name.as_str().into()),
+            ("change", c.change.to_string().into()),
+        ]))
+        .collect());
+    let commits = |commits: &[CommitSummary]| Node::List(commits.iter()
+        .map(|c| Node::Object(vec![
+            ("id", c.id.as_str().into()),
+            ("summary", c.summary.as_str().into()),
+            ("author", c.author.as_str().into()),
+            ("time", (c.time.max(0) as u64).into()),
+        ]))
+        .collect());
+    Node::Object(vec![
+        ("local", changes(&status.local)),
+        ("outgoing", commits(&status.outgoing)),
+        ("incoming", commits(&status.incoming)),
+        ("incoming_items", changes(&status.incoming_items)),
+        ("conflicts", changes(&status.conflicts)),
+    ])
+}
+
+fn resolution_node(c: &Conflict, r: &Resolution) -> Node {
+    let kept = match r {
+        Resolution::KeepLocal => "local",
+        Resolution::KeepRemote => "remote",
+        Resolution::KeepBoth => "both",
+        Resolution::Merge(_) => "merged",
+    };
+    Node::Object(vec![
+        ("group", c.group.as_str().into()),
+        ("name", c.name.as_str().into()),
+        ("kept", kept.into()),
+    ])
+}
+
+// Decides what becomes of an item changed differently on both sides of a
+// sync. Without a strategy, people are asked on a terminal, while both
+// versions are kept otherwise.
+fn resolve_conflict(strategy: Option<Strategy>, c: &Conflict) -> Resolution {
+    let strategy = match strategy {
+        Some(s) => s,
+        None if interactive() && !output::is_structured() => ask_strategy(c),
+        None => Strategy::KeepBoth,
+    };
+    match strategy {
+        Strategy::KeepLocal => Resolution::KeepLocal,
+        Strategy::KeepRemote => Resolution::KeepRemote,
+        Strategy::KeepBoth => Resolution::KeepBoth,
+        Strategy::Edit => edit_conflict(c),
+    }
+}
+
+// Returns the text values of both sides of a conflict, when both are text
+// and neither was removed, which is required to edit them together.
+fn conflict_texts(c: &Conflict) -> Option<(String, String)> {
+    let text = |item: &Option<Item>| item.as_ref().and_then(|i| i.value.as_text().map(str::to_string));
+    Some((text(&c.local)?, text(&c.remote)?))
+}
+
+// Asks which version of an item to keep, only offering to edit them
+// together when both are text.
+fn ask_strategy(c: &Conflict) -> Strategy {
+    let describe = |item: &Option<Item>| match item {
+        None => "removed".red().to_string(),
+        Some(i) => truncate_output(&i.value.to_string()),
+    };
+    ox_eprintln!("{}->{} was changed on both sides", c.group.blue(), c.name.blue());
+    eprintln!("  local:  {}", describe(&c.local));
+    eprintln!("  remote: {}", describe(&c.remote));
+    let editable = conflict_texts(c).is_some();
+    loop {
+        if editable {
+            eprint!("Keep [l]ocal, [r]emote, [b]oth, or [e]dit them together? ");
+        } else {
+            eprint!("Keep [l]ocal, [r]emote or [b]oth? ");
+        }
+        let _ = std::io::stderr().flush();
+        let mut answer = String::new();
+        // Giving up on the question keeps both versions, losing nothing
+        if std::io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            return Strategy::KeepBoth;
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "l" | "local" => return Strategy::KeepLocal,
+            "r" | "remote" => return Strategy::KeepRemote,
+            "b" | "both" | "" => return Strategy::KeepBoth,
+            "e" | "edit" if editable => return Strategy::Edit,
+            _ => {}
+        }
+    }
+}
+
+// Opens both versions of an item in the editor, separated by conflict
+// markers, and keeps the edited text. Items removed on one side or holding
+// binary values cannot be edited, and editors need a terminal, so both
+// versions are kept instead, saying so.
+fn edit_conflict(c: &Conflict) -> Resolution {
+    let (local, remote) = match conflict_texts(c) {
+        Some(texts) => texts,
+        None => {
+            ox_eprintln!("{}->{} is not text on both sides, so it cannot be edited; kept both versions", c.group.blue(), c.name.blue());
+            return Resolution::KeepBoth;
         }
-        Ok(e) => e
     };
+    if !interactive() {
+        ox_eprintln!("Editor unavailable without a terminal; kept both versions of {}->{}", c.group.blue(), c.name.blue());
+        return Resolution::KeepBoth;
+    }
+    let contents = format!("<<<<<<< local\n{}\n=======\n{}\n>>>>>>> remote\n", local, remote);
+    match edit::edit(&contents) {
+        Ok(mut merged) => {
+            trim_newline(&mut merged);
+            Resolution::Merge(Value::Text(merged))
+        }
+        Err(e) => {
+            ox_eprintln!("Editor unavailable ({}); kept both versions of {}->{}", e, c.group.blue(), c.name.blue());
+            Resolution::KeepBoth
+        }
+    }
+}
 
-    if let Some(mut i) = entry {
-        value = match i.fill_value() {
-            Err(e) => {
-                ox_eprintln!("Error reading item: {}", e);
-                exit(1)
+// sync init, sync merge
+
+fn handle_sync_command(result: oxio::Result<usize>) {
+    match result {
+        Err(e) => ox_fail!(error: e, "Error executing: {}", e),
+        Ok(items) if output::is_structured() => output::emit(&Node::Object(vec![("items", items.into())])),
+        Ok(items) => ox_println!("Done! {} item(s) in the local repository. Use {} to sync changes.", format!("{}", items).magenta(), "oxio sync".yellow())
+    }
+}
+
+fn handle_migrate(store: &Store) {
+    match store.migrate() {
+        Err(e) => ox_fail!(error: e, "Error migrating items: {}", e),
+        Ok(len) if output::is_structured() => output::emit(&Node::Object(vec![("migrated", len.into())])),
+        Ok(0) => ox_println!("All items are up to date."),
+        Ok(len) => ox_println!("Migration completed. {} item(s) upgraded", len)
+    }
+    auto_commit(store);
+}
+
+fn handle_reindex(store: &Store) {
+    match store.reindex() {
+        Err(e) => ox_fail!(error: e, "Error reindexing: {}", e),
+        Ok(r) if output::is_structured() => {
+            let corrupt = r.corrupt.iter()
+                .map(|c| Node::Object(vec![
+                    ("filename", c.filename.as_str().into()),
+                    ("error", c.error.to_string().into()),
+                ]))
+                .collect();
+            output::emit(&Node::Object(vec![
+                ("items", r.items.into()),
+                ("corrupt", Node::List(corrupt)),
+            ]))
+        }
+        Ok(r) => {
+            for c in &r.corrupt {
+                ox_eprintln!("Skipped {}: {}", c.filename, c.error);
             }
-            Ok(()) => i.value
+            ox_println!("Reindex completed. {} item(s)", r.items)
         }
     }
+}
+
+fn handle_edit_command(store: &Store, group: &str, item: &str) {
+    if !is_valid_name(group) {
+        ox_fail!(error: Error::invalid_name("group", group), "Invalid group name {}", group.yellow());
+    }
+
+    if !is_valid_name(item) {
+        ox_fail!(error: Error::invalid_name("item", item), "Invalid item name {}", item.blue());
+    }
+
+    let value = match store.get(group, item) {
+        Err(e) => ox_fail!(error: e, "Error reading item: {}", e),
+        Ok(Some(i)) => match i.value {
+            Value::Text(s) => s,
+            Value::Binary(_) => {
+                ox_fail!("{} (in {}) holds a binary value, and cannot be edited", item.blue(), group.yellow())
+            }
+        },
+        Ok(None) => String::new(),
+    };
 
     let mut edited = match edit::edit(&value) {
         Err(e) => {
-            ox_eprintln!("There was a problem with your editor: {}", e);
-            exit(1)
+            let e = Error::Editor(e);
+            ox_fail!(error: e, e)
         }
         Ok(val) => val
     };
     trim_newline(&mut edited);
 
-    match fs::create_item(&group, &item, &mut edited.to_string()) {
-        Err(e) => {
-            ox_eprintln!("Error writing item: {}", e);
-            exit(1)
+    match store.set(group, item, &edited) {
+        Err(e) => ox_fail!(error: e, "Error writing item: {}", e),
+        Ok(()) => print_written(store, group, item, &truncate_output(&edited)),
+    }
+    auto_commit(store);
+}
+
+// Formats a timestamp, in seconds since the epoch, as a UTC date.
+fn format_timestamp(secs: u64) -> String {
+    // Civil date from days since the epoch, per Howard Hinnant's algorithm.
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let rem = secs % 86400;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+fn revision_value(r: &Revision) -> String {
+    match &r.item {
+        None => "removed".red().to_string(),
+        Some(i) => truncate_output(&i.value.to_string()).magenta().to_string(),
+    }
+}
+
+fn handle_history(store: &Store, group: &str, name: &str) {
+    let revisions = match store.history(group, name) {
+        Err(e) => ox_fail!(error: e, "Error reading history: {}", e),
+        Ok(r) if r.is_empty() => {
+            ox_fail!(code: code::NOT_FOUND, "No history for {} (in {})", name.blue(), group.yellow())
         }
-        Ok(()) => {
-            ox_println!("Ok, {} (in {}) is {}", item.blue(), group.yellow(), truncate_output(&mut edited).magenta());
+        Ok(r) => r,
+    };
+    if output::is_structured() {
+        let nodes = revisions.iter()
+            .map(|r| Node::Object(vec![
+                ("commit", r.commit.id.as_str().into()),
+                ("summary", r.commit.summary.as_str().into()),
+                ("author", r.commit.author.as_str().into()),
+                ("time", (r.commit.time.max(0) as u64).into()),
+                ("item", r.item.as_ref().map(|i| item_node(store, i)).unwrap_or(Node::Null)),
+            ]))
+            .collect();
+        return output::emit(&Node::List(nodes));
+    }
+    for r in &revisions {
+        println!("{}", commit_line(&r.commit));
+        println!("  {}", revision_value(r));
+    }
+}
+
+fn handle_restore(store: &Store, group: &str, name: &str, rev: Option<&str>) {
+    match store.restore(group, name, rev) {
+        Err(e) => ox_fail!(error: e, "Error restoring {} (in {}): {}", name.blue(), group.yellow(), e),
+        Ok(_) if output::is_structured() => print_written(store, group, name, ""),
+        Ok(r) => ox_println!("Restored {} (in {}) from {}: {}", name.blue(), group.yellow(), r.commit.id.yellow(), revision_value(&r)),
+    }
+    auto_commit(store);
+}
+
+fn handle_show_command(ctx: &Context, group: Option<&str>, name: &str, id_only: bool) {
+    let (from, item) = match lookup(ctx, group, name) {
+        Some(found) => found,
+        None => ox_fail!(code: code::NOT_FOUND, "Could not find an item named {}", name.blue()),
+    };
+
+    if output::is_structured() {
+        let node = if id_only {
+            Node::Object(vec![("id", item.id().into())])
+        } else {
+            item_node(&from, &item)
+        };
+        return output::emit(&node);
+    }
+    if id_only {
+        println!("{}", item.id());
+        return;
+    }
+    let meta = &item.meta;
+    println!("{}", origin(ctx, &from, &item));
+    println!("  {}: {}", "id".cyan(), item.id());
+    println!("  {}: {}", "content type".cyan(), meta.content_type);
+    println!("  {}: {} bytes", "length".cyan(), item.summary.length);
+    if let Some(description) = &meta.description {
+        println!("  {}: {}", "description".cyan(), description);
+    }
+    if !meta.tags.is_empty() {
+        println!("  {}: {}", "tags".cyan(), meta.tags.join(", "));
+    }
+    if let Some(created) = meta.created {
+        println!("  {}: {}", "created".cyan(), format_timestamp(created));
+    }
+    if let Some(updated) = meta.updated {
+        println!("  {}: {}", "updated".cyan(), format_timestamp(updated));
+    }
+    println!("  {}: {}", "value".cyan(), preview(&item).magenta());
+}
+
+fn store_node(name: &str, path: &std::path::Path) -> Node {
+    Node::Object(vec![
+        ("name", name.into()),
+        ("path", path.to_string_lossy().into_owned().into()),
+    ])
+}
+
+// stores, stores list, stores add, stores remove, stores default
+
+fn handle_stores_command(mut stores: Stores, cmd: StoresCommand) {
+    match cmd {
+        StoresCommand::List => {
+            let mut nodes = vec![];
+            for e in stores.list() {
+                let is_default = e.name == stores.default_name();
+                let remote = Store::named(&e.name, &e.path).remote_url().ok().flatten();
+                if output::is_structured() {
+                    nodes.push(Node::Object(vec![
+                        ("name", e.name.as_str().into()),
+                        ("path", e.path.to_string_lossy().into_owned().into()),
+                        ("default", is_default.into()),
+                        ("remote", remote.into()),
+                    ]));
+                    continue;
+                }
+                let marker = if is_default { "*" } else { " " };
+                let remote = match remote {
+                    Some(url) => format!(" (syncs with {})", url),
+                    None => String::new(),
+                };
+                println!("{} {} {}{}", marker, e.name.cyan(), e.path.display(), remote);
+            }
+            if output::is_structured() {
+                output::emit(&Node::List(nodes));
+            }
+            return;
+        }
+        StoresCommand::Add { name, path } => {
+            match stores.add(&name, path) {
+                Err(e) => ox_fail!(error: e, "Error adding store: {}", e),
+                Ok(e) if output::is_structured() => output::emit(&store_node(&e.name, &e.path)),
+                Ok(e) => ox_println!("Added store {} at {}", e.name.cyan(), e.path.display())
+            }
+        }
+        StoresCommand::Remove { name } => {
+            match stores.remove(&name) {
+                Err(e) => ox_fail!(error: e, "Error removing store: {}", e),
+                Ok(e) if output::is_structured() => output::emit(&store_node(&e.name, &e.path)),
+                Ok(e) => ox_println!("Removed store {}. Its items were kept at {}", e.name.cyan(), e.path.display())
+            }
         }
+        StoresCommand::Default { name } => {
+            if let Err(e) = stores.set_default(&name) {
+                ox_fail!(error: e, "Error setting default store: {}", e);
+            }
+            if output::is_structured() {
+                output::emit(&Node::Object(vec![("default", name.as_str().into())]));
+            } else {
+                ox_println!("{} is now the default store", name.cyan())
+            }
+        }
+    }
+
+    if let Err(e) = stores.save() {
+        ox_fail!(error: e, "Error saving stores: {}", e);
     }
 }
 
 fn main() {
-    let mut args = std::env::args().skip(1);
-    match args.len() {
-        1 => handle_one_word(&mut args),
-        2 => handle_two_words(&mut args),
-        3 => handle_three_words(&mut args),
-        _ => help(),
+    let args = match std::env::args_os().skip(1).map(|a| a.into_string()).collect() {
+        Err(a) => output::fail(code::USAGE, &format!("Argument {} is not valid UTF-8", a.to_string_lossy()), None),
+        Ok(a) => a
+    };
+    let invocation = match cli::parse(args, stdin_piped()) {
+        Err(e) => output::fail(code::USAGE, &e, Some(&format!("Use {} for available options.", "oxio help".yellow()))),
+        Ok(i) => i
+    };
+    output::set_format(invocation.format);
+    let command = match invocation.command {
+        Err(e) => output::fail(code::USAGE, &e, Some(&format!("Use {} for available options.", "oxio help".yellow()))),
+        Ok(c) => c
+    };
+    let command = match command {
+        Command::Help => return help(),
+        Command::Version if output::is_structured() => {
+            return output::emit(&Node::Object(vec![("version", env!("CARGO_PKG_VERSION").into())]))
+        }
+        Command::Version => return println!("oxio {}", env!("CARGO_PKG_VERSION")),
+        command => command,
+    };
+
+    // Loaded only once needed, so a broken registry cannot get in the way of
+    // help and version
+    let stores = match Stores::load() {
+        Err(e) => ox_fail!(error: e, "Error loading stores: {}", e),
+        Ok(s) => s
+    };
+    let command = match command {
+        Command::Stores(cmd) => return handle_stores_command(stores, cmd),
+        command => command,
+    };
+
+    let store = match invocation.store {
+        Some(s) => stores.resolve(&s),
+        None => stores.open_default(),
+    };
+    let store = match store {
+        Err(Error::NotFound { name, .. }) => output::fail(code::NOT_FOUND, &format!("No store named {}", name),
+                                                          Some(&format!("Use ./{} to refer to a directory.", name))),
+        Err(e) => ox_fail!(error: e, e),
+        Ok(s) => s
+    };
+    let ctx = Context { stores, store, all_stores: invocation.all_stores };
+    let store = &ctx.store;
+    match command {
+        Command::List { group } => handle_list(&ctx, group.as_deref()),
+        Command::Get { group, name } => handle_get(&ctx, group.as_deref(), &name),
+        Command::Set { group, name, value, trim } => handle_set(store, &group, &name, read_value(value, trim)),
+        Command::Remove { group, name } => handle_remove(store, &group, &name),
+        Command::RemoveGroup { group } => handle_remove_group(store, &group),
+        Command::Edit { group, name } => handle_edit_command(store, &group, &name),
+        Command::Show { group, name, id_only } => handle_show_command(&ctx, group.as_deref(), &name, id_only),
+        Command::Pick { query } => handle_pick(&ctx, &query),
+        Command::Search { query, threshold } => handle_search(&ctx, &query, threshold),
+        Command::Grep { pattern, ignore_case, names } => handle_grep(&ctx, &pattern, ignore_case, names),
+        Command::Sync { strategy, dry_run } => handle_sync(store, strategy, dry_run),
+        Command::SyncStatus => handle_sync_status(store),
+        Command::History { group, name } => handle_history(store, &group, &name),
+        Command::Restore { group, name, rev } => handle_restore(store, &group, &name, rev.as_deref()),
+        Command::SyncInit { url } => handle_sync_command(store.sync_init(&url, print_progress)),
+        Command::SyncMerge { url } => handle_sync_command(store.sync_merge(&url, print_progress)),
+        Command::Reindex => handle_reindex(store),
+        Command::Migrate => handle_migrate(store),
+        Command::Help | Command::Version | Command::Stores(_) => unreachable!(),
     }
 }
@@ -0,0 +1,160 @@
+//! Scoring of items against a query, used by lookups and searches.
+//!
+//! Scores range from 0 to 1, and each kind of match takes its own band, so
+//! that a stronger kind always outranks a weaker one:
+//!
+//! | Kind                          | Score        |
+//! |-------------------------------|--------------|
+//! | Exact                         | 1            |
+//! | Prefix                        | 0.8 to 0.95  |
+//! | Substring                     | 0.6 to 0.75  |
+//! | Subsequence                   | 0.4 to 0.55  |
+//! | Edit distance                 | 0 to 0.4     |
+//!
+//! Within a band, matches covering more of the text, or with fewer gaps or
+//! edits, score higher.
+
+use crate::entities::Item;
+use crate::levenshtein;
+
+/// Minimum score of a match, unless set through OXIO_MATCH_THRESHOLD. Edit
+/// distance matches need half of their characters right to reach it.
+pub const DEFAULT_THRESHOLD: f64 = 0.2;
+
+// Group matches rank slightly below name matches of the same kind.
+const GROUP_WEIGHT: f64 = 0.9;
+
+/// Matcher scores items against a query, matching it against their name and
+/// their `group/name` path, and optionally against their group.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    threshold: f64,
+    groups: bool,
+}
+
+impl Default for Matcher {
+    fn default() -> Self {
+        Matcher::new()
+    }
+}
+
+impl Matcher {
+    /// Creates a matcher using the threshold set in OXIO_MATCH_THRESHOLD,
+    /// or [`DEFAULT_THRESHOLD`], and not matching groups. Thresholds outside
+    /// of 0 to 1 are clamped, and ones that are not numbers are ignored.
+    pub fn new() -> Matcher {
+        let threshold = std::env::var("OXIO_MATCH_THRESHOLD").ok()
+            .and_then(|v| v.trim().parse::<f64>().ok())
+            .filter(|t| !t.is_nan())
+            .map_or(DEFAULT_THRESHOLD, |t| t.clamp(0.0, 1.0));
+        Matcher { threshold, groups: false }
+    }
+
+    /// Sets the minimum score of a match, between 0 and 1. Thresholds that
+    /// are not numbers leave the current one unchanged.
+    pub fn threshold(mut self, threshold: f64) -> Matcher {
+        if !threshold.is_nan() {
+            self.threshold = threshold.clamp(0.0, 1.0);
+        }
+        self
+    }
+
+    /// Sets whether queries are also matched against groups.
+    pub fn groups(mut self, groups: bool) -> Matcher {
+        self.groups = groups;
+        self
+    }
+
+    /// Returns how well an item matches a query, or None when its score is
+    /// below the threshold.
+    pub fn score(&self, query: &str, item: &Item) -> Option<f64> {
+        let path = format!("{}/{}", item.group, item.name);
+        let mut best = score(query, &item.name).max(score(query, &path));
+        if self.groups {
+            best = best.max(score(query, &item.group) * GROUP_WEIGHT);
+        }
+        if best >= self.threshold && best > 0.0 {
+            Some(best)
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns how well a query matches a text, ignoring case.
+pub fn score(query: &str, text: &str) -> f64 {
+    let query = query.to_lowercase();
+    let text = text.to_lowercase();
+    let q_len = query.chars().count();
+    let t_len = text.chars().count();
+    if q_len == 0 || t_len == 0 {
+        return 0.0;
+    }
+    let coverage = q_len as f64 / t_len as f64;
+
+    if query == text {
+        1.0
+    } else if text.starts_with(&query) {
+        0.8 + 0.15 * coverage
+    } else if text.contains(&query) {
+        0.6 + 0.15 * coverage
+    } else if let Some(span) = subsequence_span(&query, &text) {
+        0.4 + 0.15 * q_len as f64 / span as f64
+    } else {
+        let distance = levenshtein::distance(&query, &text);
+        0.4 * (1.0 - distance as f64 / q_len.max(t_len) as f64)
+    }
+}
+
+// Returns how many characters of the text lie between the first and last
+// characters of the query, when all of them appear in order.
+fn subsequence_span(query: &str, text: &str) -> Option<usize> {
+    let mut chars = text.chars().enumerate();
+    let mut first = None;
+    let mut last = 0;
+    for q in query.chars() {
+        let (pos, _) = chars.find(|(_, c)| *c == q)?;
+        first.get_or_insert(pos);
+        last = pos;
+    }
+    first.map(|f| last - f + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds_of_matches_score_within_their_band() {
+        assert_eq!(score("magic", "magic"), 1.0);
+        assert_eq!(score("MAGIC", "magic"), 1.0);
+        let bands = [
+            ("mag", "magic", 0.8, 0.95),
+            ("agi", "magic", 0.6, 0.75),
+            ("mgc", "magic", 0.4, 0.55),
+            ("magoc", "magic", 0.0, 0.4),
+        ];
+        for (query, text, min, max) in bands.iter() {
+            let s = score(query, text);
+            assert!(s >= *min && s <= *max, "{} scored {} against {}", query, s, text);
+        }
+        assert_eq!(score("", "magic"), 0.0);
+        assert_eq!(score("xyz", "abc"), 0.0);
+    }
+
+    #[test]
+    fn better_matches_score_higher_within_a_band() {
+        assert!(score("mag", "magic") > score("mag", "magnificent"));
+        assert!(score("mgc", "magic") > score("mgc", "megalomaniac"));
+        assert!(score("magoc", "magic") > score("mxgoc", "magic"));
+    }
+
+    #[test]
+    fn thresholds_are_kept_between_0_and_1() {
+        let item = Item::new("gif", "magic", "");
+        assert!(Matcher::new().threshold(-1.0).score("xyz", &item).is_none());
+        assert!(Matcher::new().threshold(5.0).score("magic", &item).is_some());
+        assert!(Matcher::new().threshold(f64::NAN).score("magic", &item).is_some());
+        assert_eq!(Matcher::new().threshold(0.5).threshold(f64::NAN).threshold, 0.5);
+    }
+}
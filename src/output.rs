@@ -0,0 +1,521 @@
+use std::io::ErrorKind;
+use std::process::exit;
+use std::sync::OnceLock;
+
+use colored::Colorize;
+
+use oxio::{Error, Item, Store, Value};
+
+/// Format determines how results and errors are written. Text is meant for
+/// people, while the remaining formats follow a stable schema for scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Yaml,
+    Tsv,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_str() {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "yaml" => Some(Format::Yaml),
+            "tsv" => Some(Format::Tsv),
+            _ => None,
+        }
+    }
+}
+
+/// Exit codes reported on failure, allowing scripts to tell failures apart.
+/// These are documented in the README, and must not change.
+pub mod code {
+    pub const ERROR: i32 = 1;
+    pub const USAGE: i32 = 2;
+    pub const NOT_FOUND: i32 = 3;
+    pub const AMBIGUOUS: i32 = 4;
+    pub const INVALID_NAME: i32 = 5;
+    pub const CORRUPT: i32 = 6;
+    pub const CONFLICT: i32 = 7;
+    pub const AUTH: i32 = 8;
+    pub const CLIPBOARD: i32 = 9;
+}
+
+/// Returns the exit code reported for a given error.
+pub fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::Other(_) | Error::Io { .. } | Error::Git(_) | Error::Editor(_) => code::ERROR,
+        Error::NotFound { .. } => code::NOT_FOUND,
+        Error::Ambiguous { .. } => code::AMBIGUOUS,
+        Error::InvalidName { .. } => code::INVALID_NAME,
+        Error::Corrupt { .. } => code::CORRUPT,
+        Error::Conflict { .. } => code::CONFLICT,
+        Error::Auth { .. } => code::AUTH,
+        Error::Clipboard(_) => code::CLIPBOARD,
+    }
+}
+
+/// Suggests how to address an error, when there is something to suggest.
+pub fn hint(e: &Error) -> Option<String> {
+    Some(match e {
+        Error::Io { source, path: Some(path) } if source.kind() == ErrorKind::PermissionDenied => {
+            format!("Check the permissions of {}.", path.display())
+        }
+        Error::Corrupt { path, .. } if path.ends_with(".index") => {
+            format!("Use {} to rebuild the index.", "oxio reindex".yellow())
+        }
+        Error::Corrupt { path, .. } => format!("Restore or remove {}, then use {}.", path.display(), "oxio reindex".yellow()),
+        Error::NotFound { what: "store", .. } => format!("Use {} to list known stores.", "oxio stores".yellow()),
+        Error::Ambiguous { .. } => {
+            format!("Use {} to choose one, or list preferred groups in OXIO_GROUP_PRIORITY.", "oxio GROUP NAME".yellow())
+        }
+        Error::InvalidName { what: "store", .. } => {
+            "Store names may only hold letters, digits, dashes, underscores and dots.".to_string()
+        }
+        Error::InvalidName { .. } => "Names must not be empty, nor contain control characters.".to_string(),
+        Error::Conflict { .. } => format!("Run {} again to reconcile with the remote.", "oxio sync".yellow()),
+        Error::Auth { .. } => {
+            "Load your key with ssh-add or point OXIO_SSH_KEY to it. For HTTPS remotes, set OXIO_GIT_TOKEN \
+             or configure a git credential helper.".to_string()
+        }
+        Error::Clipboard(_) => "Pipe the output of oxio instead, as in oxio NAME | cat.".to_string(),
+        Error::Editor(_) => "Set $VISUAL or $EDITOR to the editor you want to use.".to_string(),
+        _ => return None,
+    })
+}
+
+// Names each exit code in structured error reports.
+fn code_name(c: i32) -> &'static str {
+    match c {
+        code::USAGE => "usage",
+        code::NOT_FOUND => "not_found",
+        code::AMBIGUOUS => "ambiguous",
+        code::INVALID_NAME => "invalid_name",
+        code::CORRUPT => "corrupt",
+        code::CONFLICT => "conflict",
+        code::AUTH => "auth",
+        code::CLIPBOARD => "clipboard",
+        _ => "error",
+    }
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+
+/// Sets the format used for the rest of the invocation. Colors are disabled
+/// for structured formats, so messages embedded in them are plain text.
+pub fn set_format(format: Format) {
+    if format != Format::Text {
+        colored::control::set_override(false);
+    }
+    let _ = FORMAT.set(format);
+}
+
+pub fn format() -> Format {
+    *FORMAT.get().unwrap_or(&Format::Text)
+}
+
+pub fn is_structured() -> bool {
+    format() != Format::Text
+}
+
+/// Node is a document to be emitted in one of the structured formats.
+pub enum Node {
+    Null,
+    Bool(bool),
+    Number(u64),
+    Float(f64),
+    Text(String),
+    List(Vec<Node>),
+    Object(Vec<(&'static str, Node)>),
+}
+
+impl From<&str> for Node {
+    fn from(s: &str) -> Self {
+        Node::Text(s.to_string())
+    }
+}
+
+impl From<String> for Node {
+    fn from(s: String) -> Self {
+        Node::Text(s)
+    }
+}
+
+impl From<u64> for Node {
+    fn from(n: u64) -> Self {
+        Node::Number(n)
+    }
+}
+
+impl From<usize> for Node {
+    fn from(n: usize) -> Self {
+        Node::Number(n as u64)
+    }
+}
+
+impl From<f64> for Node {
+    fn from(n: f64) -> Self {
+        Node::Float(n)
+    }
+}
+
+impl From<bool> for Node {
+    fn from(b: bool) -> Self {
+        Node::Bool(b)
+    }
+}
+
+impl<T: Into<Node>> From<Option<T>> for Node {
+    fn from(o: Option<T>) -> Self {
+        o.map(Into::into).unwrap_or(Node::Null)
+    }
+}
+
+impl<T: Into<Node>> From<Vec<T>> for Node {
+    fn from(v: Vec<T>) -> Self {
+        Node::List(v.into_iter().map(Into::into).collect())
+    }
+}
+
+/// Describes an item, including its full value. Binary values are encoded
+/// using base64, as indicated by the encoding field.
+pub fn item_node(store: &Store, item: &Item) -> Node {
+    let (encoding, value) = match &item.value {
+        Value::Text(s) => ("utf-8", s.clone()),
+        Value::Binary(b) => ("base64", base64(b)),
+    };
+    Node::Object(vec![
+        ("store", store.name().into()),
+        ("group", item.group.as_str().into()),
+        ("name", item.name.as_str().into()),
+        ("id", item.id().into()),
+        ("filename", item.filename.as_str().into()),
+        ("content_type", item.meta.content_type.as_str().into()),
+        ("encoding", encoding.into()),
+        ("value", value.into()),
+        ("length", item.value.len().into()),
+        ("created", item.meta.created.into()),
+        ("updated", item.meta.updated.into()),
+        ("tags", item.meta.tags.clone().into()),
+        ("description", item.meta.description.clone().into()),
+    ])
+}
+
+/// Writes a document to stdout using the current format.
+pub fn emit(node: &Node) {
+    let out = match format() {
+        Format::Text | Format::Json => json(node) + "\n",
+        Format::Yaml => yaml(node),
+        Format::Tsv => tsv(node),
+    };
+    print!("{}", out);
+}
+
+/// Reports an error and exits with the provided code. Structured formats
+/// report errors as a JSON document on stderr, holding the message, the kind
+/// of error, its exit code and an optional hint.
+pub fn fail(exit_code: i32, message: &str, hint: Option<&str>) -> ! {
+    if is_structured() {
+        let error = Node::Object(vec![
+            ("message", message.into()),
+            ("kind", code_name(exit_code).into()),
+            ("code", (exit_code as u64).into()),
+            ("hint", hint.into()),
+        ]);
+        eprintln!("{}", json(&Node::Object(vec![("error", error)])));
+    } else {
+        eprintln!("{}: {}", "oxio".red(), message);
+        if let Some(hint) = hint {
+            eprintln!("{}: {}", "oxio".red(), hint);
+        }
+    }
+    exit(exit_code)
+}
+
+/// Reports an error along with its hint, exiting with its exit code.
+pub fn fail_with(e: &Error, message: &str) -> ! {
+    fail(exit_code(e), message, hint(e).as_deref())
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json(node: &Node) -> String {
+    match node {
+        Node::Null => "null".to_string(),
+        Node::Bool(b) => b.to_string(),
+        Node::Number(n) => n.to_string(),
+        Node::Float(n) => format!("{:.3}", n),
+        Node::Text(s) => json_string(s),
+        Node::List(items) => {
+            format!("[{}]", items.iter().map(json).collect::<Vec<_>>().join(","))
+        }
+        Node::Object(fields) => {
+            let fields = fields.iter()
+                .map(|(k, v)| format!("{}:{}", json_string(k), json(v)))
+                .collect::<Vec<_>>();
+            format!("{{{}}}", fields.join(","))
+        }
+    }
+}
+
+// Strings are always written as double-quoted scalars, which YAML reads
+// using the same escapes as JSON.
+fn yaml(node: &Node) -> String {
+    let mut out = String::new();
+    match node {
+        Node::List(_) | Node::Object(_) => yaml_block(node, 0, &mut out),
+        scalar => {
+            out.push_str(&json(scalar));
+            out.push('\n');
+        }
+    }
+    out
+}
+
+fn yaml_block(node: &Node, indent: usize, out: &mut String) {
+    let pad = " ".repeat(indent);
+    match node {
+        Node::List(items) if items.is_empty() => out.push_str(&format!("{}[]\n", pad)),
+        Node::Object(fields) if fields.is_empty() => out.push_str(&format!("{}{{}}\n", pad)),
+        Node::List(items) => {
+            for item in items {
+                match item {
+                    Node::List(v) if !v.is_empty() => {
+                        out.push_str(&format!("{}-\n", pad));
+                        yaml_block(item, indent + 2, out);
+                    }
+                    Node::Object(v) if !v.is_empty() => {
+                        // The first field goes on the same line as the dash
+                        let mut nested = String::new();
+                        yaml_block(item, indent + 2, &mut nested);
+                        out.push_str(&format!("{}- {}", pad, &nested[indent + 2..]));
+                    }
+                    scalar => out.push_str(&format!("{}- {}\n", pad, yaml_inline(scalar))),
+                }
+            }
+        }
+        Node::Object(fields) => {
+            for (key, value) in fields {
+                match value {
+                    Node::List(v) if !v.is_empty() => {
+                        out.push_str(&format!("{}{}:\n", pad, key));
+                        yaml_block(value, indent + 2, out);
+                    }
+                    Node::Object(v) if !v.is_empty() => {
+                        out.push_str(&format!("{}{}:\n", pad, key));
+                        yaml_block(value, indent + 2, out);
+                    }
+                    scalar => out.push_str(&format!("{}{}: {}\n", pad, key, yaml_inline(scalar))),
+                }
+            }
+        }
+        scalar => out.push_str(&format!("{}{}\n", pad, yaml_inline(scalar))),
+    }
+}
+
+fn yaml_inline(node: &Node) -> String {
+    match node {
+        Node::List(v) if v.is_empty() => "[]".to_string(),
+        Node::Object(v) if v.is_empty() => "{}".to_string(),
+        other => json(other),
+    }
+}
+
+// Lists are written as one row per element, and anything else as a single
+// row, preceded by a header naming each column. Nested lists of scalars are
+// joined by commas, and other nested values are written as JSON.
+fn tsv(node: &Node) -> String {
+    let rows: Vec<&Node> = match node {
+        Node::List(items) => items.iter().collect(),
+        other => vec![other],
+    };
+    let mut out = String::new();
+    if let Some(Node::Object(fields)) = rows.first() {
+        let header = fields.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+        out.push_str(&header.join("\t"));
+        out.push('\n');
+    }
+    for row in rows {
+        let cells = match row {
+            Node::Object(fields) => fields.iter().map(|(_, v)| tsv_cell(v)).collect(),
+            other => vec![tsv_cell(other)],
+        };
+        out.push_str(&cells.join("\t"));
+        out.push('\n');
+    }
+    out
+}
+
+fn tsv_cell(node: &Node) -> String {
+    match node {
+        Node::Null => String::new(),
+        Node::Bool(b) => b.to_string(),
+        Node::Number(n) => n.to_string(),
+        Node::Float(n) => format!("{:.3}", n),
+        Node::Text(s) => tsv_escape(s),
+        Node::List(items) if items.iter().all(|i| matches!(i, Node::Text(_) | Node::Number(_))) => {
+            items.iter().map(tsv_cell).collect::<Vec<_>>().join(",")
+        }
+        other => tsv_escape(&json(other)),
+    }
+}
+
+fn tsv_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(fields: Vec<(&'static str, Node)>) -> Node {
+        Node::Object(fields)
+    }
+
+    #[test]
+    fn json_escapes_strings() {
+        assert_eq!(json_string("say \"hi\""), r#""say \"hi\"""#);
+        assert_eq!(json_string(r"C:\temp"), r#""C:\\temp""#);
+        assert_eq!(json_string("a\tb\nc\rd"), r#""a\tb\nc\rd""#);
+        assert_eq!(json_string("\u{0}\u{1b}[0m\u{7f}"), r#""\u0000\u001b[0m\u007f""#);
+        assert_eq!(json_string("ünïcode ✓"), "\"ünïcode ✓\"");
+    }
+
+    #[test]
+    fn json_writes_nested_documents() {
+        let doc = object(vec![
+            ("name", "magic".into()),
+            ("length", 3usize.into()),
+            ("score", 0.5.into()),
+            ("tags", vec!["a", "b"].into()),
+            ("description", Option::<String>::None.into()),
+        ]);
+        assert_eq!(json(&doc), r#"{"name":"magic","length":3,"score":0.500,"tags":["a","b"],"description":null}"#);
+    }
+
+    #[test]
+    fn tsv_escapes_cells() {
+        let doc = Node::List(vec![
+            object(vec![("name", "a\tb".into()), ("value", "line 1\nline 2\r\n".into())]),
+            object(vec![("name", r#"say "hi""#.into()), ("value", r"C:\temp\n".into())]),
+        ]);
+        assert_eq!(tsv(&doc), concat!(
+            "name\tvalue\n",
+            "a\\tb\tline 1\\nline 2\\r\\n\n",
+            "say \"hi\"\tC:\\\\temp\\\\n\n",
+        ));
+    }
+
+    #[test]
+    fn tsv_joins_lists_and_writes_other_values_as_json() {
+        let doc = object(vec![
+            ("tags", vec!["a", "b"].into()),
+            ("nested", object(vec![("k", "v\t".into())])),
+            ("none", Node::Null),
+        ]);
+        assert_eq!(tsv(&doc), "tags\tnested\tnone\na,b\t{\"k\":\"v\\\\t\"}\t\n");
+    }
+
+    #[test]
+    fn base64_pads_partial_chunks() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Many"), "TWFueQ==");
+        assert_eq!(base64(&[0xff, 0xfe, 0x00]), "//4A");
+    }
+
+    #[test]
+    fn yaml_writes_nested_lists_of_objects() {
+        let doc = Node::List(vec![
+            object(vec![
+                ("name", "magic".into()),
+                ("tags", vec!["a", "b"].into()),
+                ("revisions", Node::List(vec![
+                    object(vec![("commit", "abc".into()), ("value", 1u64.into())]),
+                    object(vec![("commit", "def".into()), ("value", Node::Null)]),
+                ])),
+                ("empty", Node::List(vec![])),
+            ]),
+            Node::List(vec!["x".into(), Node::Object(vec![])]),
+        ]);
+        assert_eq!(yaml(&doc), concat!(
+            "- name: \"magic\"\n",
+            "  tags:\n",
+            "    - \"a\"\n",
+            "    - \"b\"\n",
+            "  revisions:\n",
+            "    - commit: \"abc\"\n",
+            "      value: 1\n",
+            "    - commit: \"def\"\n",
+            "      value: null\n",
+            "  empty: []\n",
+            "-\n",
+            "  - \"x\"\n",
+            "  - {}\n",
+        ));
+    }
+
+    #[test]
+    fn yaml_quotes_strings() {
+        let doc = object(vec![
+            ("bool", "yes".into()),
+            ("null", "null".into()),
+            ("number", "0755".into()),
+            ("mapping", "a: b".into()),
+            ("comment", "# not a comment".into()),
+            ("sequence", "- item".into()),
+            ("empty", "".into()),
+            ("lines", "one\n\"two\"".into()),
+        ]);
+        assert_eq!(yaml(&doc), concat!(
+            "bool: \"yes\"\n",
+            "null: \"null\"\n",
+            "number: \"0755\"\n",
+            "mapping: \"a: b\"\n",
+            "comment: \"# not a comment\"\n",
+            "sequence: \"- item\"\n",
+            "empty: \"\"\n",
+            "lines: \"one\\n\\\"two\\\"\"\n",
+        ));
+        assert_eq!(yaml(&"on".into()), "\"on\"\n");
+    }
+}
@@ -0,0 +1,305 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+use colored::Colorize;
+use oxio::matcher;
+
+// Maximum amount of entries shown at once.
+const MAX_HEIGHT: usize = 15;
+
+/// Entry is a line offered by the picker. Entries are filtered by their
+/// label, while the preview is only displayed next to it.
+pub struct Entry {
+    pub label: String,
+    pub preview: String,
+}
+
+/// Lets the user pick one of the provided entries, narrowing them down as a
+/// query is typed, starting with the provided one. Returns the index of the
+/// chosen entry, or None when the picker was dismissed. The picker is drawn
+/// on the controlling terminal, so stdout may be redirected elsewhere.
+pub fn pick(entries: &[Entry], query: &str) -> io::Result<Option<usize>> {
+    let mut term = Terminal::open()?;
+    let (rows, cols) = term.size();
+    let mut picker = Picker {
+        entries,
+        query: query.to_string(),
+        matches: vec![],
+        selected: 0,
+        offset: 0,
+        height: MAX_HEIGHT.min(entries.len()).min(rows.saturating_sub(1)).max(1),
+        width: cols.max(20),
+    };
+    picker.filter();
+
+    // Make room below the cursor, so drawing never scrolls the screen.
+    term.write(&format!("{}\x1b[{}A", "\r\n".repeat(picker.height), picker.height))?;
+    let result = loop {
+        term.write(&picker.render())?;
+        match term.read_key()? {
+            Key::Enter => break picker.matches.get(picker.selected).copied(),
+            Key::Cancel => break None,
+            Key::Up => picker.move_by(-1),
+            Key::Down => picker.move_by(1),
+            Key::Backspace => {
+                picker.query.pop();
+                picker.filter();
+            }
+            Key::ClearLine => {
+                picker.query.clear();
+                picker.filter();
+            }
+            Key::Char(c) => {
+                picker.query.push(c);
+                picker.filter();
+            }
+            Key::None => {}
+        }
+    };
+    term.write("\r\x1b[J")?;
+    Ok(result)
+}
+
+struct Picker<'a> {
+    entries: &'a [Entry],
+    query: String,
+    // Indexes of entries matching the query, best matches first.
+    matches: Vec<usize>,
+    selected: usize,
+    offset: usize,
+    height: usize,
+    width: usize,
+}
+
+impl Picker<'_> {
+    // Ranks entries as lookups do, keeping the ones scoring at least the
+    // default threshold. Every entry matches an empty query.
+    fn filter(&mut self) {
+        if self.query.is_empty() {
+            self.matches = (0..self.entries.len()).collect();
+        } else {
+            let mut scored: Vec<(f64, usize)> = self.entries.iter()
+                .enumerate()
+                .map(|(n, e)| (matcher::score(&self.query, &e.label), n))
+                .filter(|(s, _)| *s >= matcher::DEFAULT_THRESHOLD)
+                .collect();
+            scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+            self.matches = scored.into_iter().map(|(_, n)| n).collect();
+        }
+        self.selected = 0;
+        self.offset = 0;
+    }
+
+    fn move_by(&mut self, delta: isize) {
+        if self.matches.is_empty() {
+            return;
+        }
+        let last = self.matches.len() - 1;
+        self.selected = (self.selected as isize + delta).clamp(0, last as isize) as usize;
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected >= self.offset + self.height {
+            self.offset = self.selected + 1 - self.height;
+        }
+    }
+
+    // Draws the prompt followed by visible matches, leaving the cursor at
+    // the end of the query.
+    fn render(&self) -> String {
+        let mut out = format!("\r\x1b[J{} {}  {}", ">".cyan(), self.query,
+                              format!("{}/{}", self.matches.len(), self.entries.len()).dimmed());
+        let visible = self.matches.iter().enumerate().skip(self.offset).take(self.height);
+        let mut lines = 0;
+        for (n, &i) in visible {
+            let entry = &self.entries[i];
+            let label = clip_width(&entry.label, self.width - 3);
+            let room = (self.width - 3).saturating_sub(label.chars().count() + 2);
+            let preview = clip_width(&entry.preview, room);
+            let line = if n == self.selected {
+                format!("{} {}  {}", ">".magenta(), label.bold(), preview.magenta())
+            } else {
+                format!("  {}  {}", label, preview.dimmed())
+            };
+            out.push_str("\r\n");
+            out.push_str(&line);
+            lines += 1;
+        }
+        if lines > 0 {
+            out.push_str(&format!("\x1b[{}A", lines));
+        }
+        out.push_str(&format!("\r\x1b[{}C", 2 + self.query.chars().count()));
+        out
+    }
+}
+
+// Shortens a string to fit the provided amount of columns, replacing
+// control characters so a value cannot break the layout.
+fn clip_width(s: &str, width: usize) -> String {
+    let s: String = s.chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+    if s.chars().count() <= width {
+        return s;
+    }
+    let mut clipped: String = s.chars().take(width.saturating_sub(3)).collect();
+    clipped.push_str("...");
+    clipped
+}
+
+enum Key {
+    Char(char),
+    Backspace,
+    ClearLine,
+    Up,
+    Down,
+    Enter,
+    Cancel,
+    None,
+}
+
+// Terminal holds the controlling terminal in raw mode, restoring its
+// previous settings once dropped. Settings are changed through stty, which
+// is available on every Unix.
+struct Terminal {
+    tty: File,
+    saved: String,
+}
+
+impl Terminal {
+    fn open() -> io::Result<Terminal> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        let saved = stty(&tty, &["-g"])?.trim().to_string();
+        // Reads give up after a tenth of a second, so a lone escape key can
+        // be told apart from the start of an escape sequence.
+        stty(&tty, &["raw", "-echo", "min", "0", "time", "1"])?;
+        Ok(Terminal { tty, saved })
+    }
+
+    // Returns the amount of rows and columns of the terminal.
+    fn size(&self) -> (usize, usize) {
+        let size = stty(&self.tty, &["size"]).unwrap_or_default();
+        let mut parts = size.split_whitespace().map(|p| p.parse().unwrap_or(0));
+        match (parts.next(), parts.next()) {
+            (Some(rows), Some(cols)) if rows > 0 && cols > 0 => (rows, cols),
+            _ => (24, 80),
+        }
+    }
+
+    fn write(&mut self, s: &str) -> io::Result<()> {
+        self.tty.write_all(s.as_bytes())?;
+        self.tty.flush()
+    }
+
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0u8; 1];
+        match self.tty.read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    fn read_key(&mut self) -> io::Result<Key> {
+        let b = loop {
+            if let Some(b) = self.byte()? {
+                break b;
+            }
+        };
+        Ok(match b {
+            b'\r' | b'\n' => Key::Enter,
+            // Ctrl-C and Ctrl-D
+            3 | 4 => Key::Cancel,
+            8 | 127 => Key::Backspace,
+            // Ctrl-N and Ctrl-P
+            14 => Key::Down,
+            16 => Key::Up,
+            // Ctrl-U
+            21 => Key::ClearLine,
+            27 => self.escape()?,
+            b if b < 0x20 => Key::None,
+            b => self.utf8(b)?,
+        })
+    }
+
+    // Reads the remainder of an escape sequence, of which only arrow keys
+    // are handled.
+    fn escape(&mut self) -> io::Result<Key> {
+        match self.byte()? {
+            None => Ok(Key::Cancel),
+            Some(b'[') | Some(b'O') => {
+                let mut last = self.byte()?;
+                while let Some(b'0'..=b'9') | Some(b';') = last {
+                    last = self.byte()?;
+                }
+                Ok(match last {
+                    Some(b'A') => Key::Up,
+                    Some(b'B') => Key::Down,
+                    _ => Key::None,
+                })
+            }
+            Some(_) => Ok(Key::None),
+        }
+    }
+
+    fn utf8(&mut self, first: u8) -> io::Result<Key> {
+        let len = match first {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        let mut bytes = vec![first];
+        while bytes.len() < len {
+            match self.byte()? {
+                Some(b) => bytes.push(b),
+                None => break,
+            }
+        }
+        Ok(match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+            Some(c) => Key::Char(c),
+            None => Key::None,
+        })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = stty(&self.tty, &[&self.saved]);
+    }
+}
+
+fn stty(tty: &File, args: &[&str]) -> io::Result<String> {
+    let out = Command::new("stty")
+        .args(args)
+        .stdin(tty.try_clone()?)
+        .stderr(Stdio::null())
+        .output()?;
+    if !out.status.success() {
+        return Err(io::Error::other("could not configure the terminal"));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(labels: &[&str], query: &str) -> Vec<String> {
+        let entries = labels.iter()
+            .map(|l| Entry { label: l.to_string(), preview: String::new() })
+            .collect::<Vec<_>>();
+        let mut picker = Picker {
+            entries: &entries, query: query.to_string(), matches: vec![],
+            selected: 0, offset: 0, height: MAX_HEIGHT, width: 80,
+        };
+        picker.filter();
+        picker.matches.iter().map(|&n| labels[n].to_string()).collect()
+    }
+
+    #[test]
+    fn entries_are_ranked_as_lookups() {
+        let labels = ["words->magenta", "gif->magic", "png->logo"];
+        assert_eq!(matches(&labels, ""), labels);
+        assert_eq!(matches(&labels, "mag"), vec!["gif->magic", "words->magenta"]);
+        assert_eq!(matches(&labels, "LOGO"), vec!["png->logo"]);
+    }
+}
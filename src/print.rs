@@ -1,19 +1,41 @@
 #[macro_export]
 macro_rules! ox_eprintln {
    ($msg:expr) => {
-        eprintln!("{}: {}", "oxio".red(), $msg);
+        eprintln!("{}: {}", "oxio".red(), $msg)
    };
    ($msg:expr, $($e:tt)*) => {
-        eprintln!("{}: {}", "oxio".red(), format!($msg, $($e)*));
+        eprintln!("{}: {}", "oxio".red(), format!($msg, $($e)*))
    };
 }
 
 #[macro_export]
 macro_rules! ox_println {
     ($msg:expr) => {
-        println!("{}: {}", "oxio".cyan(), $msg);
+        println!("{}: {}", "oxio".cyan(), $msg)
     };
     ($msg:expr, $($e:tt)*) => {
-        println!("{}: {}", "oxio".cyan(), format!($msg, $($e)*));
+        println!("{}: {}", "oxio".cyan(), format!($msg, $($e)*))
    };
 }
+
+#[macro_export]
+macro_rules! ox_fail {
+    (error: $err:expr, $msg:expr) => {
+        $crate::output::fail_with(&$err, &$msg.to_string())
+    };
+    (error: $err:expr, $msg:expr, $($e:tt)*) => {
+        $crate::output::fail_with(&$err, &format!($msg, $($e)*))
+    };
+    (code: $code:expr, $msg:expr) => {
+        $crate::output::fail($code, &$msg.to_string(), None)
+    };
+    (code: $code:expr, $msg:expr, $($e:tt)*) => {
+        $crate::output::fail($code, &format!($msg, $($e)*), None)
+    };
+    ($msg:expr) => {
+        $crate::output::fail($crate::output::code::ERROR, &$msg.to_string(), None)
+    };
+    ($msg:expr, $($e:tt)*) => {
+        $crate::output::fail($crate::output::code::ERROR, &format!($msg, $($e)*), None)
+    };
+}
@@ -1,37 +1,126 @@
 use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
 use core::fmt;
 
-pub(crate) type Result<T> = std::result::Result<T, Error>;
-pub(crate) type Operation = Result<()>;
+use git2::ErrorCode;
 
+pub type Result<T> = std::result::Result<T, Error>;
+pub type Operation = Result<()>;
+
+/// Error describes a failure. Variants identify what failed, carrying the
+/// path, item or store involved along with the underlying error, if any, so
+/// callers can match on them and report them differently.
+#[derive(Debug)]
 pub enum Error {
-    Err(Box<String>)
+    /// A failure not covered by any other variant.
+    Other(String),
+    /// Reading or writing a file failed.
+    Io { source: io::Error, path: Option<PathBuf> },
+    /// A git operation on the sync repository failed.
+    Git(git2::Error),
+    /// An item or index could not be decoded.
+    Corrupt { path: PathBuf, reason: String },
+    /// An item, group or store does not exist. What names the kind of entity.
+    NotFound { what: &'static str, name: String },
+    /// A lookup matched more than one item equally well.
+    Ambiguous { query: String, candidates: Vec<String> },
+    /// A group, item or store name cannot be used.
+    InvalidName { what: &'static str, name: String },
+    /// Local and remote changes could not be reconciled.
+    Conflict { message: String, source: Option<git2::Error> },
+    /// Authenticating against a remote failed.
+    Auth { message: String, source: Option<git2::Error> },
+    /// The clipboard could not be written.
+    Clipboard(String),
+    /// The editor could not be started, or failed.
+    Editor(io::Error),
 }
 
 impl Error {
     pub fn new<S: AsRef<str> + ?Sized>(msg: &S) -> Error {
-        Error::Err(Box::from(String::from(msg.as_ref())))
+        Error::Other(String::from(msg.as_ref()))
+    }
+
+    pub fn not_found(what: &'static str, name: &str) -> Error {
+        Error::NotFound { what, name: name.to_string() }
     }
-}
 
+    pub fn ambiguous(query: &str, candidates: Vec<String>) -> Error {
+        Error::Ambiguous { query: query.to_string(), candidates }
+    }
+
+    pub fn invalid_name(what: &'static str, name: &str) -> Error {
+        Error::InvalidName { what, name: name.to_string() }
+    }
+
+    pub fn conflict<S: AsRef<str> + ?Sized>(msg: &S) -> Error {
+        Error::Conflict { message: String::from(msg.as_ref()), source: None }
+    }
+
+    pub fn auth<S: AsRef<str> + ?Sized>(msg: &S) -> Error {
+        Error::Auth { message: String::from(msg.as_ref()), source: None }
+    }
+}
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Error::Err(msg) => write!(f, "{}", msg)
+            Error::Other(msg) | Error::Clipboard(msg) => write!(f, "{}", msg),
+            Error::Io { source, path: Some(path) } => write!(f, "{}: {}", path.display(), source),
+            Error::Io { source, path: None } => write!(f, "{}", source),
+            Error::Git(e) => write!(f, "{}", message(e)),
+            Error::Corrupt { path, reason } => {
+                write!(f, "Invalid or corrupt entry at {}: {}", path.display(), reason)
+            }
+            Error::NotFound { what, name } => write!(f, "No {} named {}", what, name),
+            Error::Ambiguous { query, candidates } => {
+                write!(f, "{} matches more than one item: {}", query, candidates.join(", "))
+            }
+            Error::InvalidName { what, name } => write!(f, "Invalid {} name {}", what, name),
+            Error::Conflict { message, .. } | Error::Auth { message, .. } => write!(f, "{}", message),
+            Error::Editor(e) => write!(f, "Could not run the editor: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } | Error::Editor(source) => Some(source),
+            Error::Git(e) => Some(e),
+            Error::Conflict { source, .. } | Error::Auth { source, .. } => {
+                source.as_ref().map(|e| e as &(dyn std::error::Error + 'static))
+            }
+            _ => None,
         }
     }
 }
 
 impl From<git2::Error> for Error {
     fn from(e: git2::Error) -> Self {
-        e.as_ox_error()
+        let message = message(&e).to_string();
+        match e.code() {
+            ErrorCode::Auth | ErrorCode::Certificate => Error::Auth { message, source: Some(e) },
+            ErrorCode::Conflict
+            | ErrorCode::MergeConflict
+            | ErrorCode::NotFastForward
+            | ErrorCode::Unmerged => Error::Conflict { message, source: Some(e) },
+            _ => Error::Git(e),
+        }
     }
 }
 
-impl From<std::io::Error> for Error {
-    fn from(e: std::io::Error) -> Self {
-        e.as_ox_error()
+// Errors returned from git2 callbacks, such as credential requests, come
+// back with their class and code appended to the message.
+fn message(e: &git2::Error) -> &str {
+    let msg = e.message();
+    msg.find("; class=").map_or(msg, |i| &msg[..i])
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io { source: e, path: None }
     }
 }
 
@@ -45,13 +134,13 @@ pub trait OxResult<T, E> {
 
 impl OxError for git2::Error {
     fn as_ox_error(&self) -> Error {
-        Error::Err(Box::from(self.message().to_string()))
+        Error::from(git2::Error::new(self.code(), self.class(), self.message()))
     }
 }
 
-impl OxError for std::io::Error {
+impl OxError for io::Error {
     fn as_ox_error(&self) -> Error {
-        Error::Err(Box::from(self.to_string()))
+        Error::from(io::Error::new(self.kind(), self.to_string()))
     }
 }
 
@@ -63,3 +152,14 @@ impl<T, E> OxResult<T, E> for core::result::Result<T, E> where E: OxError {
         }
     }
 }
+
+/// IoContext attaches the path involved to I/O failures.
+pub trait IoContext<T> {
+    fn at(self, path: &Path) -> Result<T>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn at(self, path: &Path) -> Result<T> {
+        self.map_err(|source| Error::Io { source, path: Some(path.to_path_buf()) })
+    }
+}
@@ -0,0 +1,422 @@
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::entities::{CorruptItem, Item, Reindex, Value};
+use crate::fs;
+use crate::grep;
+use crate::grep::GrepMatch;
+use crate::lock;
+use crate::lock::{LockKind, StoreLock};
+use crate::matcher::Matcher;
+use crate::result::{Error, Operation, Result};
+use crate::sync;
+use crate::sync::{CanSync, Conflict, Resolution, Revision, SyncStatus, SyncStep};
+
+/// Store represents a snippet cache on disk. All operations performed through
+/// a Store are confined to the directory it was opened at, and coordinate
+/// with other processes through an advisory lock: reads hold a shared lock,
+/// while writes and syncs hold an exclusive one. Operations waiting for a
+/// lock give up after 10 seconds, or after the amount of seconds set in
+/// OXIO_LOCK_TIMEOUT.
+///
+/// Changes written to a synced store are committed by [`Store::sync`], or
+/// earlier through [`Store::commit_changes`], in commits naming the items
+/// they changed.
+#[derive(Debug, Clone)]
+pub struct Store {
+    path: PathBuf,
+    name: Option<String>,
+}
+
+impl Store {
+    /// Opens a store located at the provided path. The directory is not
+    /// required to exist, and will be created once the first item is written
+    /// into it.
+    pub fn open<P: AsRef<Path>>(path: P) -> Store {
+        Store { path: path.as_ref().to_path_buf(), name: None }
+    }
+
+    /// Opens a store located at the provided path, identified by a name.
+    /// See [`crate::stores::Stores`].
+    pub fn named<P: AsRef<Path>>(name: &str, path: P) -> Store {
+        Store { path: path.as_ref().to_path_buf(), name: Some(name.to_string()) }
+    }
+
+    /// Opens the store at the default location used by the oxio binary.
+    /// The location is taken from OXIO_HOME when set, falling back to the
+    /// legacy ~/.oxio.cache directory when it exists, and then to
+    /// $XDG_DATA_HOME/oxio (or ~/.local/share/oxio).
+    pub fn open_default() -> Result<Store> {
+        Ok(Store::open(fs::cache_path()?))
+    }
+
+    /// Returns the path this store operates on.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the name this store was registered with, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Returns an exact match for the provided group and name, with its value
+    /// already loaded.
+    pub fn get(&self, group: &str, name: &str) -> Result<Option<Item>> {
+        let _lock = self.lock(LockKind::Shared)?;
+        self.loaded(fs::get_item(&self.path, group, name)?)
+    }
+
+    /// Finds an item by name or `group/name` path, tolerating partial names
+    /// and typos. The returned item has its value already loaded. Fails with
+    /// [`Error::Ambiguous`] when several items match equally well; see
+    /// [`Store::matches`].
+    pub fn find(&self, name: &str) -> Result<Option<Item>> {
+        let _lock = self.lock(LockKind::Shared)?;
+        let mut matches = fs::best_matches(fs::search(&self.path, &Matcher::new(), name)?, |i| &i.group);
+        if matches.len() > 1 {
+            let candidates = matches.iter().map(|i| format!("{}->{}", i.group, i.name)).collect();
+            return Err(Error::ambiguous(name, candidates));
+        }
+        self.loaded(matches.pop())
+    }
+
+    /// Returns the items matching the provided name best: all items with the
+    /// highest score, narrowed down to the group listed first in
+    /// OXIO_GROUP_PRIORITY (a comma-separated list of groups) when set. More
+    /// than one item means the name is ambiguous. Values are not loaded.
+    pub fn matches(&self, name: &str) -> Result<Vec<Item>> {
+        let _lock = self.lock(LockKind::Shared)?;
+        Ok(fs::best_matches(fs::search(&self.path, &Matcher::new(), name)?, |i| &i.group))
+    }
+
+    /// Returns items matching a query along with their score, best first.
+    /// See [`crate::matcher`] for how items are scored. Values are not
+    /// loaded; use [`Store::load`] to fill them.
+    pub fn search(&self, matcher: &Matcher, query: &str) -> Result<Vec<(f64, Item)>> {
+        let _lock = self.lock(LockKind::Shared)?;
+        fs::search(&self.path, matcher, query)
+    }
+
+    /// Returns items whose text values match a pattern, along with the
+    /// matching lines, and optionally items whose `group/name` path matches
+    /// it. Values are matched line by line. See [`crate::grep`].
+    pub fn grep(&self, pattern: &Regex, paths: bool) -> Result<Vec<GrepMatch>> {
+        let _lock = self.lock(LockKind::Shared)?;
+        grep::grep(&self.path, pattern, paths)
+    }
+
+    /// Returns all indexed items. Values are not loaded; use [`Store::load`]
+    /// to fill them.
+    pub fn list(&self) -> Result<Vec<Item>> {
+        let _lock = self.lock(LockKind::Shared)?;
+        fs::get_all_items(&self.path)
+    }
+
+    /// Returns the files of the store that cannot be read as items, along
+    /// with their errors. Such files are left out of [`Store::list`].
+    pub fn unreadable(&self) -> Result<Vec<CorruptItem>> {
+        let _lock = self.lock(LockKind::Shared)?;
+        fs::corrupt_items(&self.path)
+    }
+
+    /// Returns all indexed items belonging to a given group. Values are not
+    /// loaded; use [`Store::load`] to fill them.
+    pub fn list_group(&self, group: &str) -> Result<Vec<Item>> {
+        let _lock = self.lock(LockKind::Shared)?;
+        fs::get_all_group(&self.path, group)
+    }
+
+    /// Loads the value of an item obtained through [`Store::list`] or
+    /// [`Store::list_group`].
+    pub fn load(&self, item: &mut Item) -> Operation {
+        let _lock = self.lock(LockKind::Shared)?;
+        item.fill_value(&self.path)
+    }
+
+    /// Sets the value of an item, creating it in case it does not exist.
+    /// Metadata of an existing item is kept.
+    /// Binary values have their content type guessed from their contents.
+    pub fn set<V: Into<Value>>(&self, group: &str, name: &str, value: V) -> Operation {
+        let value = value.into();
+        let _lock = self.lock(LockKind::Exclusive)?;
+        let item = match self.loaded(fs::get_item(&self.path, group, name)?)? {
+            Some(mut existing) => {
+                if value.is_binary() || existing.value.is_binary() {
+                    existing.meta.content_type = value.guess_content_type().to_string();
+                }
+                existing.value = value;
+                existing
+            }
+            None => Item::new(group, name, value),
+        };
+        self.write(&item)
+    }
+
+    /// Writes an item along with its metadata, replacing any item with the
+    /// same group and name. Its update time is set to the current time.
+    pub fn save(&self, item: &Item) -> Operation {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        self.write(item)
+    }
+
+    fn write(&self, item: &Item) -> Operation {
+        if !is_valid_name(&item.group) {
+            return Err(Error::invalid_name("group", &item.group));
+        }
+        if !is_valid_name(&item.name) {
+            return Err(Error::invalid_name("item", &item.name));
+        }
+        fs::create_item(&self.path, item)
+    }
+
+    /// Upgrades items written in older formats to the current one, returning
+    /// how many items were migrated.
+    pub fn migrate(&self) -> Result<usize> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        fs::migrate(&self.path)
+    }
+
+    /// Removes an item from a group. Returns whether the item existed.
+    pub fn delete(&self, group: &str, name: &str) -> Result<bool> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        let item = match fs::get_item(&self.path, group, name)? {
+            None => return Ok(false),
+            Some(i) => i,
+        };
+        fs::remove_items(&self.path, &[item])?;
+        Ok(true)
+    }
+
+    /// Removes a group and all its items. Returns how many items were removed.
+    pub fn delete_group(&self, group: &str) -> Result<usize> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        let items = fs::get_all_group(&self.path, group)?;
+        if items.is_empty() {
+            return Ok(0);
+        }
+        fs::remove_items(&self.path, &items)?;
+        Ok(items.len())
+    }
+
+    /// Rebuilds the store index. Items that cannot be read are left out of
+    /// the index and reported in the returned [`Reindex`].
+    pub fn reindex(&self) -> Result<Reindex> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        fs::index(&self.path)
+    }
+
+    /// Determines whether the store can be synchronised with a remote.
+    pub fn can_sync(&self) -> Result<CanSync> {
+        sync::can_sync_cache(&self.path)
+    }
+
+    /// Commits the changes written to a synced store since its last commit,
+    /// without pushing them. Stores that are not synced, or are in the
+    /// middle of another operation, are left alone.
+    pub fn commit_changes(&self) -> Operation {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        sync::commit_changes(&self.path)
+    }
+
+    /// Returns the URL of the remote this store syncs with, if any.
+    pub fn remote_url(&self) -> Result<Option<String>> {
+        sync::remote_url(&self.path)
+    }
+
+    /// Synchronises the store with its remote and updates its index, returning
+    /// how many items are present afterwards. Items changed differently on
+    /// both sides are kept both, as described by [`Resolution::KeepBoth`].
+    pub fn sync<F: FnMut(SyncStep)>(&self, progress: F) -> Result<usize> {
+        self.sync_with(|_| Resolution::KeepBoth, progress)
+    }
+
+    /// Same as [`Store::sync`], deciding what becomes of items changed
+    /// differently on both sides through the provided function.
+    pub fn sync_with<R, F>(&self, mut resolve: R, mut progress: F) -> Result<usize>
+        where R: FnMut(&Conflict) -> Resolution, F: FnMut(SyncStep) {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        let repo = sync::get_local_repository(&self.path)?;
+        let changed = sync::perform_sync(&repo, &mut resolve, &mut progress)?;
+        fs::refresh(&self.path, &changed)
+    }
+
+    /// Reports how the store differs from its remote: items changed since
+    /// the last sync, and commits yet to be pushed or merged. The remote is
+    /// contacted, and its new commits are downloaded into the store's
+    /// repository, but neither items, references nor the remote are changed.
+    /// Downloading writes to the repository, so the store is locked
+    /// exclusively meanwhile.
+    pub fn sync_status(&self) -> Result<SyncStatus> {
+        let repo = sync::get_local_repository(&self.path)?;
+        let _lock = self.lock(LockKind::Exclusive)?;
+        sync::status(&repo)
+    }
+
+    /// Lists the versions of an item recorded in the store's repository,
+    /// newest first, including the commits removing it. Only synced stores
+    /// keep a history, and changes not yet committed are not part of it.
+    pub fn history(&self, group: &str, name: &str) -> Result<Vec<Revision>> {
+        let _lock = self.lock(LockKind::Shared)?;
+        let repo = sync::history_repository(&self.path)?;
+        sync::history(&repo, group, name)
+    }
+
+    /// Writes back a previous version of an item, which may have been
+    /// removed since. The version is taken from a revision, such as a commit
+    /// ID listed by [`Store::history`], or otherwise is the most recent one
+    /// holding a different value than the current one. Returns the restored
+    /// version.
+    pub fn restore(&self, group: &str, name: &str, rev: Option<&str>) -> Result<Revision> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        let repo = sync::history_repository(&self.path)?;
+        let current = self.loaded(fs::get_item(&self.path, group, name)?)?;
+        let path = format!("{}->{}", group, name);
+        let revision = match rev {
+            Some(rev) => {
+                let revision = sync::revision(&repo, group, name, rev)?;
+                if revision.item.is_none() {
+                    return Err(Error::not_found("item", &format!("{} at {}", path, rev)));
+                }
+                revision
+            }
+            None => sync::history(&repo, group, name)?
+                .into_iter()
+                .find(|r| match (&r.item, &current) {
+                    (None, _) => false,
+                    (Some(i), Some(c)) => i.value != c.value,
+                    (Some(_), None) => true,
+                })
+                .ok_or_else(|| Error::not_found("other version of item", &path))?,
+        };
+        self.write(revision.item.as_ref().unwrap())?;
+        Ok(revision)
+    }
+
+    /// Initialises an empty store with the contents of a remote repository.
+    pub fn sync_init<F: FnMut(SyncStep)>(&self, url: &str, mut progress: F) -> Result<usize> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        sync::init_sync_empty(&self.path, url.to_string(), &mut progress)
+    }
+
+    /// Merges the items in this store into a remote repository, and starts
+    /// tracking it.
+    pub fn sync_merge<F: FnMut(SyncStep)>(&self, url: &str, mut progress: F) -> Result<usize> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+        sync::init_sync_existing(&self.path, url.to_string(), &mut progress)
+    }
+
+    fn loaded(&self, item: Option<Item>) -> Result<Option<Item>> {
+        match item {
+            None => Ok(None),
+            Some(mut i) => {
+                i.fill_value(&self.path)?;
+                Ok(Some(i))
+            }
+        }
+    }
+
+    fn lock(&self, kind: LockKind) -> Result<StoreLock> {
+        if kind == LockKind::Exclusive {
+            fs::ensure_cache(&self.path)?;
+        }
+        lock::lock(&self.path, kind)
+    }
+}
+
+/// Returns whether a given string can be used as a group or item name.
+pub fn is_valid_name(name: &str) -> bool {
+    fs::is_valid_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+
+    // TempStore is a store in a temporary directory, removed once dropped.
+    struct TempStore(Store);
+
+    impl TempStore {
+        fn new() -> TempStore {
+            let name: String = thread_rng().sample_iter(&Alphanumeric).take(12).map(char::from).collect();
+            TempStore(Store::open(std::env::temp_dir().join(format!("oxio-test-{}", name))))
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.0.path());
+        }
+    }
+
+    fn value(store: &Store, group: &str, name: &str) -> Option<String> {
+        store.get(group, name).unwrap().map(|i| i.value.to_string())
+    }
+
+    #[test]
+    fn items_are_set_replaced_and_removed() {
+        let t = TempStore::new();
+        let store = &t.0;
+        assert!(store.list().unwrap().is_empty());
+        assert_eq!(value(store, "gif", "magic"), None);
+
+        store.set("gif", "magic", "v1").unwrap();
+        store.set("gif", "other", "x").unwrap();
+        assert_eq!(value(store, "gif", "magic"), Some("v1".to_string()));
+        let created = store.get("gif", "magic").unwrap().unwrap().meta.created;
+
+        store.set("gif", "magic", "v2").unwrap();
+        let item = store.get("gif", "magic").unwrap().unwrap();
+        assert_eq!(item.value.to_string(), "v2");
+        assert_eq!(item.meta.created, created);
+        assert_eq!(store.list().unwrap().len(), 2);
+
+        assert!(store.delete("gif", "magic").unwrap());
+        assert!(!store.delete("gif", "magic").unwrap());
+        assert_eq!(value(store, "gif", "magic"), None);
+        assert_eq!(value(store, "gif", "other"), Some("x".to_string()));
+    }
+
+    #[test]
+    fn binary_values_and_metadata_round_trip() {
+        let t = TempStore::new();
+        let store = &t.0;
+        let png = b"\x89PNG\r\n\x1a\n\x00\xff".to_vec();
+        store.set("img", "logo", png.clone()).unwrap();
+        let item = store.get("img", "logo").unwrap().unwrap();
+        assert_eq!(item.value.as_bytes(), png.as_slice());
+        assert_eq!(item.meta.content_type, "image/png");
+
+        let mut item = Item::new("notes", "todo", "buy milk\n");
+        item.meta.tags = vec!["home".to_string()];
+        item.meta.description = Some("Groceries".to_string());
+        store.save(&item).unwrap();
+        let saved = store.get("notes", "todo").unwrap().unwrap();
+        assert_eq!(saved.value.to_string(), "buy milk\n");
+        assert_eq!(saved.meta.tags, item.meta.tags);
+        assert_eq!(saved.meta.description, item.meta.description);
+
+        let mut listed = store.list_group("notes").unwrap();
+        assert_eq!(listed.len(), 1);
+        store.load(&mut listed[0]).unwrap();
+        assert_eq!(listed[0].value.to_string(), "buy milk\n");
+    }
+
+    #[test]
+    fn groups_are_removed_with_their_items() {
+        let t = TempStore::new();
+        let store = &t.0;
+        store.set("gif", "magic", "a").unwrap();
+        store.set("gif", "other", "b").unwrap();
+        store.set("png", "logo", "c").unwrap();
+        assert_eq!(store.delete_group("gif").unwrap(), 2);
+        assert_eq!(store.delete_group("gif").unwrap(), 0);
+        let left = store.list().unwrap().into_iter().map(|i| i.name).collect::<Vec<_>>();
+        assert_eq!(left, vec!["logo"]);
+        assert!(store.set("", "name", "x").is_err());
+        assert!(store.set("group", "bad\nname", "x").is_err());
+    }
+}
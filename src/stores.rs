@@ -0,0 +1,306 @@
+use std::path::{Path, PathBuf};
+
+use crate::entities::Item;
+use crate::fs;
+use crate::matcher::Matcher;
+use crate::result::{Error, IoContext, Operation, Result};
+use crate::store::Store;
+
+/// Name of the store kept at the default cache location. It is always
+/// available, and does not need to be registered.
+pub const DEFAULT_STORE: &str = "default";
+
+/// StoreEntry represents a named store registered in the stores file.
+#[derive(Debug, Clone)]
+pub struct StoreEntry {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// Stores keeps track of named stores, each living in its own cache
+/// directory, with its own index and sync remote. The registry is persisted
+/// at $XDG_CONFIG_HOME/oxio/stores (or ~/.config/oxio/stores) as a list of
+/// `store NAME PATH` lines, and an optional `default NAME` line.
+#[derive(Debug, Clone)]
+pub struct Stores {
+    file: Option<PathBuf>,
+    entries: Vec<StoreEntry>,
+    default: String,
+}
+
+impl Stores {
+    /// Loads the stores registry from its default location. A missing
+    /// registry yields one containing only the default store.
+    pub fn load() -> Result<Stores> {
+        match fs::config_path() {
+            Ok(p) => Stores::load_from(p.join("stores")),
+            Err(_) => Ok(Stores { file: None, entries: vec![], default: DEFAULT_STORE.to_string() }),
+        }
+    }
+
+    /// Loads the stores registry from the provided file.
+    pub fn load_from<P: AsRef<Path>>(file: P) -> Result<Stores> {
+        let file = file.as_ref().to_path_buf();
+        let mut stores = Stores { file: Some(file.clone()), entries: vec![], default: DEFAULT_STORE.to_string() };
+        if !file.exists() {
+            return Ok(stores);
+        }
+
+        let contents = std::fs::read_to_string(&file).at(&file)?;
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("default"), Some(name), None) => stores.default = name.to_string(),
+                (Some("store"), Some(name), Some(path)) => stores.entries.push(StoreEntry {
+                    name: name.to_string(),
+                    path: PathBuf::from(path),
+                }),
+                _ => return Err(Error::Corrupt { path: file, reason: format!("unrecognized entry on line {}", n + 1) }),
+            }
+        }
+        Ok(stores)
+    }
+
+    /// Persists the registry.
+    pub fn save(&self) -> Operation {
+        let file = match &self.file {
+            None => return Err(Error::new("Could not determine where to keep oxio's configuration. Please set XDG_CONFIG_HOME.")),
+            Some(f) => f,
+        };
+        if let Some(parent) = file.parent() {
+            std::fs::create_dir_all(parent).at(parent)?;
+        }
+
+        let mut contents = String::from("# Stores known by oxio. Use `oxio stores` to manage this file.\n");
+        if self.default != DEFAULT_STORE {
+            contents.push_str(&format!("default {}\n", self.default));
+        }
+        for e in &self.entries {
+            contents.push_str(&format!("store {} {}\n", e.name, registrable_path(&e.path)?));
+        }
+
+        fs::write_atomic(file, contents.as_bytes())
+    }
+
+    /// Returns all known stores, starting with the default store when its
+    /// location can be determined.
+    pub fn list(&self) -> Vec<StoreEntry> {
+        let mut all = Vec::with_capacity(self.entries.len() + 1);
+        if let Ok(path) = fs::cache_path() {
+            all.push(StoreEntry { name: DEFAULT_STORE.to_string(), path });
+        }
+        all.extend(self.entries.iter().cloned());
+        all
+    }
+
+    /// Returns the name of the store used when none is explicitly requested.
+    pub fn default_name(&self) -> &str {
+        &self.default
+    }
+
+    /// Returns a store by its name.
+    pub fn get(&self, name: &str) -> Option<StoreEntry> {
+        self.list().into_iter().find(|e| e.name == name)
+    }
+
+    /// Registers a new store. When no path is provided, the store is created
+    /// under $XDG_DATA_HOME/oxio-stores (or ~/.local/share/oxio-stores).
+    /// Relative paths are taken from the current directory, and registered
+    /// as absolute ones.
+    pub fn add(&mut self, name: &str, path: Option<PathBuf>) -> Result<StoreEntry> {
+        if !is_valid_store_name(name) {
+            return Err(Error::invalid_name("store", name));
+        }
+        if self.get(name).is_some() {
+            return Err(Error::new(&format!("Store {} already exists", name)));
+        }
+        let path = match path {
+            Some(p) if p.is_relative() => std::env::current_dir()?.join(p),
+            Some(p) => p,
+            None => fs::named_stores_path()?.join(name),
+        };
+        registrable_path(&path)?;
+        if self.list().iter().any(|e| e.path == path) {
+            return Err(Error::new(&format!("{} is already used by another store", path.display())));
+        }
+        fs::ensure_cache(&path)?;
+
+        let entry = StoreEntry { name: name.to_string(), path };
+        self.entries.push(entry.clone());
+        Ok(entry)
+    }
+
+    /// Unregisters a store. Its contents are kept on disk.
+    pub fn remove(&mut self, name: &str) -> Result<StoreEntry> {
+        if name == DEFAULT_STORE {
+            return Err(Error::new("The default store cannot be removed"));
+        }
+        let pos = match self.entries.iter().position(|e| e.name == name) {
+            None => return Err(Error::not_found("store", name)),
+            Some(p) => p,
+        };
+        if self.default == name {
+            self.default = DEFAULT_STORE.to_string();
+        }
+        Ok(self.entries.remove(pos))
+    }
+
+    /// Sets the store used when none is explicitly requested.
+    pub fn set_default(&mut self, name: &str) -> Operation {
+        if self.get(name).is_none() {
+            return Err(Error::not_found("store", name));
+        }
+        self.default = name.to_string();
+        Ok(())
+    }
+
+    /// Opens a store by its name.
+    pub fn open(&self, name: &str) -> Result<Store> {
+        if name == DEFAULT_STORE {
+            return Ok(Store::named(DEFAULT_STORE, fs::cache_path()?));
+        }
+        match self.get(name) {
+            None => Err(Error::not_found("store", name)),
+            Some(e) => Ok(Store::named(&e.name, e.path)),
+        }
+    }
+
+    /// Opens the store used when none is explicitly requested.
+    pub fn open_default(&self) -> Result<Store> {
+        self.open(&self.default)
+    }
+
+    /// Opens a store given either its name or a path to a cache directory.
+    /// Values that look like a store name but are not registered are
+    /// rejected, so a typo does not silently create a new cache.
+    pub fn resolve(&self, name_or_path: &str) -> Result<Store> {
+        if self.get(name_or_path).is_some() || name_or_path == DEFAULT_STORE {
+            return self.open(name_or_path);
+        }
+        let path = PathBuf::from(shellexpand::tilde(name_or_path).to_string());
+        if is_valid_store_name(name_or_path) && !path.is_dir() {
+            return Err(Error::not_found("store", name_or_path));
+        }
+        Ok(Store::open(path))
+    }
+
+    /// Opens all known stores, starting with the default one.
+    pub fn open_all(&self) -> Result<Vec<Store>> {
+        let mut all = vec![self.open_default()?];
+        for e in self.list() {
+            if e.name != self.default {
+                all.push(Store::named(&e.name, e.path));
+            }
+        }
+        Ok(all)
+    }
+
+    /// Finds an item by name across all stores, tolerating partial names and
+    /// typos. Returns the closest match along with the store containing it.
+    /// Fails with [`Error::Ambiguous`] when several items match equally
+    /// well, including items with the same name in different stores.
+    pub fn find(&self, name: &str) -> Result<Option<(Store, Item)>> {
+        let mut matches = self.matches(name)?;
+        if matches.len() > 1 {
+            let candidates = matches.iter()
+                .map(|(s, i)| format!("{}:{}->{}", s.name().unwrap_or(DEFAULT_STORE), i.group, i.name))
+                .collect();
+            return Err(Error::ambiguous(name, candidates));
+        }
+        match matches.pop() {
+            None => Ok(None),
+            Some((store, mut item)) => {
+                store.load(&mut item)?;
+                Ok(Some((store, item)))
+            }
+        }
+    }
+
+    /// Returns the items matching the provided name best across all stores,
+    /// along with the store containing them. See [`Store::matches`].
+    pub fn matches(&self, name: &str) -> Result<Vec<(Store, Item)>> {
+        Ok(fs::best_matches(self.search(&Matcher::new(), name)?, |(_, i)| &i.group))
+    }
+
+    /// Returns items matching a query across all stores, along with their
+    /// score and the store containing them, best first. Items scoring the
+    /// same keep the order of their stores, starting with the default one.
+    /// Values are not loaded.
+    pub fn search(&self, matcher: &Matcher, query: &str) -> Result<Vec<(f64, (Store, Item))>> {
+        let mut all = vec![];
+        for store in self.open_all()? {
+            for (score, item) in store.search(matcher, query)? {
+                all.push((score, (store.clone(), item)));
+            }
+        }
+        fs::sort_by_score(&mut all);
+        Ok(all)
+    }
+
+    /// Returns an exact match for the provided group and name from the first
+    /// store containing it, starting with the default one.
+    pub fn get_item(&self, group: &str, name: &str) -> Result<Option<(Store, Item)>> {
+        for store in self.open_all()? {
+            if let Some(item) = store.get(group, name)? {
+                return Ok(Some((store, item)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Returns whether a given string can be used as a store name. Stores are
+/// created in a directory named after them by default, so `.` and `..` are
+/// refused.
+pub fn is_valid_store_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".."
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+// Returns a store path as written to the registry, which is a text file, so
+// paths that are not valid UTF-8 cannot be registered.
+fn registrable_path(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| {
+        Error::new(&format!("{} is not valid UTF-8, so it cannot be registered as a store", path.display()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::distributions::Alphanumeric;
+    use rand::{thread_rng, Rng};
+
+    #[test]
+    fn store_names_cannot_refer_to_other_directories() {
+        assert!(is_valid_store_name("work"));
+        assert!(is_valid_store_name("v1.2_old-work"));
+        assert!(is_valid_store_name(".hidden"));
+        assert!(!is_valid_store_name(""));
+        assert!(!is_valid_store_name("."));
+        assert!(!is_valid_store_name(".."));
+        assert!(!is_valid_store_name("a/b"));
+        assert!(!is_valid_store_name("a b"));
+    }
+
+    #[test]
+    fn stores_are_resolved_by_name_or_path() {
+        let name: String = thread_rng().sample_iter(&Alphanumeric).take(12).map(char::from).collect();
+        let dir = std::env::temp_dir().join(format!("oxio-test-{}", name));
+        let mut stores = Stores::load_from(dir.join("stores")).unwrap();
+        stores.add("work", Some(dir.join("work"))).unwrap();
+
+        let work = stores.resolve("work").unwrap();
+        assert_eq!((work.name(), work.path()), (Some("work"), dir.join("work").as_path()));
+        // Paths are used as they are, without consulting the registry
+        let explicit = stores.resolve(dir.join("explicit").to_str().unwrap()).unwrap();
+        assert_eq!((explicit.name(), explicit.path()), (None, dir.join("explicit").as_path()));
+        assert!(matches!(stores.resolve("typo"), Err(Error::NotFound { .. })));
+        assert_eq!(stores.resolve("./typo").unwrap().path(), Path::new("./typo"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}